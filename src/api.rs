@@ -1,65 +1,241 @@
-use std::fmt::Display;
+use std::fmt::Debug;
 
 use chrono::NaiveDate;
-use clap::ValueEnum;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder};
+
+use crate::archive::PageArchive;
+use crate::comics::Comic;
+use crate::proxy::{ProxyPool, ProxyTicket};
+use crate::rate_limit::RateLimiter;
+
+mod gocomics;
+mod local;
+mod template;
+mod uclick;
+mod wayback;
+
+pub use gocomics::Gocomics;
+pub use local::LocalSource;
+pub use template::TemplateSource;
+pub use uclick::Uclick;
+pub use wayback::Wayback;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Api<'a> {
-    pub source: Source,
-    pub proxy: Option<&'a str>,
+    /// Sources to try for each date, in order
+    pub sources: &'a [SourceStep<'a>],
+    pub comic: &'a Comic,
+    pub proxy: Option<&'a ProxyPool>,
+    /// Requests per second to each host (pages, images, and proxies)
+    pub rate_limiter: &'a RateLimiter,
+    /// Store fetched webpages, or reuse them with `--reparse`
+    pub page_archive: Option<&'a PageArchive>,
+}
+
+/// One step of the source fallback chain
+#[derive(Clone, Copy, Debug)]
+pub enum SourceStep<'a> {
+    /// Image URL from cache file, if date is cached
+    Cache,
+    /// Image URL scraped from a [Source]
+    Source(&'a dyn Source),
 }
 
-#[derive(Clone, Copy, Debug, ValueEnum)]
-pub enum Source {
-    Gocomics,
+/// A website (or archive) which image URLs can be scraped from
+///
+/// Implement this to add a new source, and add it to a [SourceRegistry]
+pub trait Source: Debug + Send + Sync {
+    /// Name used to select source with `--source`
+    fn name(&self) -> &str;
+
+    /// How to find image URL for a given comic and date
+    fn lookup(&self, comic: &Comic, date: NaiveDate) -> Lookup;
+
+    /// Find and validate image URL in webpage body, for [Lookup::Page]
+    ///
+    /// Page URL is given without proxy, eg. to resolve relative URLs
+    fn find_image_url(&self, _page_url: &str, _body: &str) -> Result<String, ExtractError> {
+        Err(ExtractError::LayoutChanged)
+    }
+
+    /// Whether webpages for [Lookup::Page] should be fetched through the proxy service
+    fn use_proxy(&self) -> bool {
+        true
+    }
+
+    /// Whether `file://` image URLs from this source may be read
+    ///
+    /// Only sources chosen by the user should read local files, not URLs from cache files or
+    /// webpages
+    fn reads_local_files(&self) -> bool {
+        false
+    }
+
+    /// Find next lookup step in search results body, for [Lookup::Search]
+    fn read_search_results(
+        &self,
+        _comic: &Comic,
+        _date: NaiveDate,
+        _body: &str,
+    ) -> Result<Lookup, ExtractError> {
+        Ok(Lookup::Unavailable)
+    }
+
+    /// Check that downloaded image is not a placeholder or error page
+    ///
+    /// An invalid image causes the next source in the fallback chain to be tried
+    fn validate_image(&self, _content_type: Option<&str>, _bytes: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Date of first comic available from source
+    ///
+    /// Defaults to the date of the first published comic
+    fn first_date(&self, comic: &Comic) -> NaiveDate {
+        comic.first_date
+    }
+
+    /// Shorten image URL, for saving to cache file
+    fn minify_image_url<'a>(&self, url: &'a str) -> &'a str {
+        url
+    }
+
+    /// Reverse of [Source::minify_image_url], for reading from cache file
+    fn expand_image_url(&self, minified: &str) -> String {
+        minified.to_string()
+    }
 }
 
-pub async fn check_proxy_service(client: &Client, proxy: &str) -> Result<(), reqwest::Error> {
-    client.get(proxy).send().await?.error_for_status()?;
-    Ok(())
+/// Result of [Source::lookup]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// Fetch webpage (through proxy, if enabled with [Source::use_proxy]), then find image URL with
+    /// [Source::find_image_url]
+    Page(String),
+    /// Image URL is known without fetching a webpage
+    Image(String),
+    /// Fetch search results (without proxy), then find next step with [Source::read_search_results]
+    Search(String),
+    /// Source does not have comic for this date
+    Unavailable,
+}
+
+/// Reason an image URL could not be found in a webpage or search results
+#[derive(thiserror::Error, Debug)]
+pub enum ExtractError {
+    /// No candidate URLs were found at all
+    #[error("Cannot find image URL in webpage. The page layout may have changed")]
+    LayoutChanged,
+
+    /// Candidate URLs were found, but none were valid image URLs
+    #[error("Found invalid image URL `{0}`. The page layout may have changed")]
+    InvalidUrl(String),
+
+    /// Search results could not be parsed
+    #[error("Malformed search results - {0}")]
+    MalformedResults(String),
+}
+
+/// List of available sources, to select from by name
+#[derive(Debug)]
+pub struct SourceRegistry {
+    sources: Vec<Box<dyn Source>>,
 }
 
 impl<'a> Api<'a> {
-    pub fn get_page_url(&self, date: NaiveDate) -> String {
-        let url = self.source.get_page_url(date);
+    /// Request for webpage, through proxy pool if enabled
+    ///
+    /// Result of request should be reported to proxy pool with ticket, if given
+    pub fn build_page_request(
+        &self,
+        client: &Client,
+        url: &str,
+    ) -> (RequestBuilder, Option<ProxyTicket>) {
         match self.proxy {
-            None => url,
-            Some(proxy) => proxy.to_string() + "?" + &url,
+            None => (client.get(url), None),
+            Some(proxy) => proxy.build_request(client, url),
         }
     }
-}
 
-impl Default for Source {
-    fn default() -> Self {
-        Self::Gocomics
+    pub fn uses_cache(&self) -> bool {
+        self.sources
+            .iter()
+            .any(|step| matches!(step, SourceStep::Cache))
     }
 }
 
-impl Display for Source {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+impl SourceStep<'_> {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Cache => SourceRegistry::CACHE_STEP,
+            Self::Source(source) => source.name(),
+        }
     }
-}
 
-impl Source {
-    fn get_page_url(&self, date: NaiveDate) -> String {
-        match &self {
-            Self::Gocomics => {
-                format!(
-                    "https://www.gocomics.com/garfield/{}",
-                    date.format("%Y/%m/%d")
-                )
-            }
+    pub fn first_date(&self, comic: &Comic) -> NaiveDate {
+        match self {
+            Self::Cache => comic.first_date,
+            Self::Source(source) => source.first_date(comic),
         }
     }
+}
+
+impl SourceRegistry {
+    /// Default fallback chain for `--source`
+    pub const DEFAULT_SOURCES: &'static str = "cache,gocomics";
+    /// Reserved name for using the cache file in a fallback chain
+    pub const CACHE_STEP: &'static str = "cache";
 
-    pub fn find_image_url<'a>(&self, body: &'a str) -> Option<&'a str> {
-        match &self {
-            Self::Gocomics => {
-                let char_index = body.find(crate::IMAGE_URL_PREFIX)?;
-                body.get(char_index..char_index + crate::IMAGE_URL_LENGTH)
-            }
+    /// Create registry with no sources
+    pub fn empty() -> Self {
+        Self {
+            sources: Vec::new(),
         }
     }
+
+    /// Add source, replacing any existing source with the same name
+    ///
+    /// Name must not be [SourceRegistry::CACHE_STEP], as this is reserved for the cache file
+    pub fn register(&mut self, source: Box<dyn Source>) {
+        self.sources
+            .retain(|existing| existing.name() != source.name());
+        self.sources.push(source);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Source> {
+        self.sources
+            .iter()
+            .find(|source| source.name().eq_ignore_ascii_case(name))
+            .map(|source| source.as_ref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sources.iter().map(|source| source.name())
+    }
+
+    /// Resolve ordered list of names into a fallback chain
+    ///
+    /// Returns the first unknown name as an error
+    pub fn get_chain<'a>(&'a self, names: &'a [String]) -> Result<Vec<SourceStep<'a>>, &'a str> {
+        names
+            .iter()
+            .map(|name| {
+                if name.eq_ignore_ascii_case(Self::CACHE_STEP) {
+                    return Ok(SourceStep::Cache);
+                }
+                self.get(name).map(SourceStep::Source).ok_or(name.as_str())
+            })
+            .collect()
+    }
+}
+
+impl Default for SourceRegistry {
+    /// Registry containing all built-in sources
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(Box::new(Gocomics));
+        registry.register(Box::new(Uclick));
+        registry.register(Box::new(Wayback::default()));
+        registry
+    }
 }
@@ -0,0 +1,89 @@
+use chrono::NaiveDate;
+use reqwest::Url;
+
+use super::{ExtractError, Lookup, Source};
+use crate::comics::Comic;
+use crate::html;
+
+/// Scrapes [gocomics.com](https://www.gocomics.com/garfield), or another comic hosted there
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Gocomics;
+
+impl Gocomics {
+    pub(super) const IMAGE_URL_PREFIX: &'static str = "https://featureassets.gocomics.com/assets/";
+    const IMAGE_HOST: &'static str = "featureassets.gocomics.com";
+
+    /// Check that URL is an image asset, and remove any query parameters
+    ///
+    /// Returns `None` if URL is not valid
+    fn validate_image_url(candidate: &str) -> Option<String> {
+        let url = Url::parse(candidate.trim()).ok()?;
+        if url.scheme() != "https" || url.host_str() != Some(Self::IMAGE_HOST) {
+            return None;
+        }
+        let id = url.path().strip_prefix("/assets/")?;
+        if id.is_empty() || !id.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(Self::IMAGE_URL_PREFIX.to_string() + id)
+    }
+}
+
+impl Source for Gocomics {
+    fn name(&self) -> &str {
+        "gocomics"
+    }
+
+    fn lookup(&self, comic: &Comic, date: NaiveDate) -> Lookup {
+        Lookup::Page(format!(
+            "https://www.gocomics.com/{}/{}",
+            comic.slug,
+            date.format("%Y/%m/%d")
+        ))
+    }
+
+    fn find_image_url(&self, _page_url: &str, body: &str) -> Result<String, ExtractError> {
+        let candidates = find_candidate_urls(body, &[Self::IMAGE_URL_PREFIX]);
+        if let Some(url) = candidates
+            .iter()
+            .find_map(|candidate| Self::validate_image_url(candidate))
+        {
+            return Ok(url);
+        }
+        match candidates.into_iter().next() {
+            Some(candidate) => Err(ExtractError::InvalidUrl(candidate)),
+            None => Err(ExtractError::LayoutChanged),
+        }
+    }
+
+    fn minify_image_url<'a>(&self, url: &'a str) -> &'a str {
+        url.strip_prefix(Self::IMAGE_URL_PREFIX).unwrap_or(url)
+    }
+
+    fn expand_image_url(&self, minified: &str) -> String {
+        // Full URL, possibly from another source
+        if minified.contains("://") {
+            return minified.to_string();
+        }
+        Self::IMAGE_URL_PREFIX.to_string() + minified
+    }
+}
+
+/// Candidate image URLs, from most to least reliable location in webpage
+///
+/// Any other occurrences of URLs starting with a prefix are included last, eg. in inline scripts
+pub(super) fn find_candidate_urls(body: &str, prefixes: &[&str]) -> Vec<String> {
+    let mut candidates = html::find_meta_contents(body, "og:image");
+    candidates.extend(html::find_json_ld_images(body));
+    candidates.extend(html::find_picture_sources(body));
+    for prefix in prefixes {
+        candidates.extend(body.match_indices(prefix).map(|(index, _)| {
+            body[index..]
+                .split(|ch: char| !ch.is_ascii_alphanumeric() && !":/.".contains(ch))
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }));
+    }
+    candidates
+}
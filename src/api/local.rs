@@ -0,0 +1,129 @@
+use chrono::NaiveDate;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use super::{Lookup, Source};
+use crate::comics::Comic;
+use crate::dates::date_from_filename;
+
+/// Images in another everygarf folder (flat or tree layout), eg. on a network drive
+///
+/// Selected with `--source file:///path/to/folder`, or a path containing a `/`
+#[derive(Debug)]
+pub struct LocalSource {
+    name: String,
+    images: HashMap<NaiveDate, PathBuf>,
+}
+
+impl LocalSource {
+    /// Create source if name is a `file://` URL or a path
+    ///
+    /// Returns `None` if name is not a path, or an error if folder cannot be read
+    pub fn from_name(name: &str) -> Option<Result<Self, String>> {
+        let folder = if name.starts_with("file://") {
+            match Url::parse(name)
+                .ok()
+                .and_then(|url| url.to_file_path().ok())
+            {
+                Some(folder) => folder,
+                None => return Some(Err(format!("Invalid file URL `{}`", name))),
+            }
+        } else if name.contains(['/', '\\']) {
+            PathBuf::from(name)
+        } else {
+            return None;
+        };
+
+        // Image URLs must be absolute
+        let folder = fs::canonicalize(&folder).unwrap_or(folder);
+        Some(
+            Self::read_folder(&folder)
+                .map(|images| Self {
+                    name: name.to_string(),
+                    images,
+                })
+                .map_err(|error| {
+                    format!(
+                        "Reading local source folder `{}` - {}",
+                        folder.display(),
+                        error
+                    )
+                }),
+        )
+    }
+
+    /// Find all images in folder, with `YYYY-MM-DD.ext` or `YYYY/MM/DD.ext` layout
+    fn read_folder(folder: &Path) -> io::Result<HashMap<NaiveDate, PathBuf>> {
+        let mut images = HashMap::new();
+
+        for child in fs::read_dir(folder)?.flatten() {
+            let path = child.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            if path.is_file() {
+                if let Some(date) = date_from_filename(name) {
+                    images.insert(date, path);
+                }
+                continue;
+            }
+
+            // Tree layout
+            let Ok(year) = name.parse::<i32>() else {
+                continue;
+            };
+            // Stray files with numeric names are skipped
+            if !path.is_dir() {
+                continue;
+            }
+            for month_dir in fs::read_dir(&path)?.flatten() {
+                let month_path = month_dir.path();
+                if !month_path.is_dir() {
+                    continue;
+                }
+                let Some(month) = parse_dir_number(&month_path) else {
+                    continue;
+                };
+                for day_file in fs::read_dir(&month_path)?.flatten() {
+                    let day_path = day_file.path();
+                    let Some(day) = parse_dir_number(&day_path) else {
+                        continue;
+                    };
+                    if let Some(date) = NaiveDate::from_ymd_opt(year, month, day) {
+                        images.insert(date, day_path);
+                    }
+                }
+            }
+        }
+
+        Ok(images)
+    }
+}
+
+/// Parse `MM` directory or `DD.ext` file name
+fn parse_dir_number(path: &Path) -> Option<u32> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+impl Source for LocalSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lookup(&self, _comic: &Comic, date: NaiveDate) -> Lookup {
+        let Some(path) = self.images.get(&date) else {
+            return Lookup::Unavailable;
+        };
+        match Url::from_file_path(path) {
+            Ok(url) => Lookup::Image(url.into()),
+            Err(()) => Lookup::Unavailable,
+        }
+    }
+
+    fn reads_local_files(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::NaiveDate;
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use super::{ExtractError, Lookup, Source, SourceRegistry};
+use crate::comics::Comic;
+use crate::html::{self, Selector};
+
+/// User-defined source, declared in a sources file
+///
+/// Page URL is a template with placeholders `{yyyy}`, `{yy}`, `{mm}`, `{dd}`, and `{comic}`
+#[derive(Debug)]
+pub struct TemplateSource {
+    name: String,
+    page_url: String,
+    first_date: Option<NaiveDate>,
+    rule: ExtractRule,
+}
+
+/// How to find image URL in webpage
+#[derive(Debug)]
+enum ExtractRule {
+    /// First capture group if present, otherwise whole match
+    Regex(Regex),
+    /// Attribute of first tag matching selector
+    Selector {
+        selector: Selector,
+        attribute: String,
+    },
+}
+
+/// Layout of sources file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourcesFile {
+    #[serde(default)]
+    source: Vec<SourceConfig>,
+}
+
+/// Single `[[source]]` table in sources file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceConfig {
+    name: String,
+    page_url: String,
+    first_date: Option<NaiveDate>,
+    regex: Option<String>,
+    selector: Option<String>,
+    attribute: Option<String>,
+}
+
+impl TemplateSource {
+    /// Read all sources declared in a TOML file
+    pub fn load_file(path: &Path) -> Result<Vec<Self>, String> {
+        let file = fs::read_to_string(path)
+            .map_err(|error| format!("Reading sources file - {}", error))?;
+        Self::parse_file(&file)
+    }
+
+    pub fn parse_file(file: &str) -> Result<Vec<Self>, String> {
+        let file: SourcesFile =
+            toml::from_str(file).map_err(|error| format!("Parsing sources file - {}", error))?;
+        file.source
+            .into_iter()
+            .map(|config| {
+                let name = config.name.clone();
+                Self::from_config(config)
+                    .map_err(|error| format!("Invalid source `{}` - {}", name, error))
+            })
+            .collect()
+    }
+
+    fn from_config(config: SourceConfig) -> Result<Self, String> {
+        if config.name.eq_ignore_ascii_case(SourceRegistry::CACHE_STEP) {
+            return Err(format!(
+                "Name `{}` is reserved for the cache file",
+                SourceRegistry::CACHE_STEP
+            ));
+        }
+
+        let rule = match (config.regex, config.selector) {
+            (Some(regex), None) => {
+                let regex = Regex::new(&regex).map_err(|error| format!("Bad regex - {error}"))?;
+                ExtractRule::Regex(regex)
+            }
+            (None, Some(selector)) => ExtractRule::Selector {
+                selector: selector.parse()?,
+                attribute: config
+                    .attribute
+                    .ok_or("Missing `attribute` for `selector`")?,
+            },
+            _ => return Err("Exactly one of `regex` or `selector` must be given".to_string()),
+        };
+
+        Ok(Self {
+            name: config.name,
+            page_url: config.page_url,
+            first_date: config.first_date,
+            rule,
+        })
+    }
+
+    fn find_candidate_urls(&self, body: &str) -> Vec<String> {
+        match &self.rule {
+            ExtractRule::Regex(regex) => regex
+                .captures_iter(body)
+                .filter_map(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .map(|found| found.as_str().to_string())
+                .collect(),
+            ExtractRule::Selector {
+                selector,
+                attribute,
+            } => html::select_attributes(body, selector, attribute),
+        }
+    }
+}
+
+impl Source for TemplateSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lookup(&self, comic: &Comic, date: NaiveDate) -> Lookup {
+        let url = self
+            .page_url
+            .replace("{yyyy}", &date.format("%Y").to_string())
+            .replace("{yy}", &date.format("%y").to_string())
+            .replace("{mm}", &date.format("%m").to_string())
+            .replace("{dd}", &date.format("%d").to_string())
+            .replace("{comic}", comic.slug);
+        Lookup::Page(url)
+    }
+
+    fn find_image_url(&self, page_url: &str, body: &str) -> Result<String, ExtractError> {
+        let candidates = self.find_candidate_urls(body);
+        let Some(candidate) = candidates.into_iter().next() else {
+            return Err(ExtractError::LayoutChanged);
+        };
+        // Relative URLs are resolved against page
+        let url = Url::parse(page_url).and_then(|page_url| page_url.join(candidate.trim()));
+        match url {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(url.into()),
+            _ => Err(ExtractError::InvalidUrl(candidate)),
+        }
+    }
+
+    fn first_date(&self, comic: &Comic) -> NaiveDate {
+        match self.first_date {
+            Some(first_date) => first_date.max(comic.first_date),
+            None => comic.first_date,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use chrono::NaiveDate;
+use reqwest::Url;
+
+use super::gocomics::find_candidate_urls;
+use super::{ExtractError, Gocomics, Lookup, Source};
+use crate::comics::Comic;
+
+/// Snapshots of gocomics.com pages in the [Wayback Machine](https://web.archive.org), found
+/// with the CDX API
+///
+/// Useful for dates where gocomics.com pages are broken
+#[derive(Clone, Debug)]
+pub struct Wayback {
+    base_url: String,
+}
+
+impl Wayback {
+    pub const DEFAULT_URL: &'static str = "https://web.archive.org";
+
+    /// Image hosts used by gocomics.com, currently and historically
+    const ASSET_PREFIXES: &'static [&'static str] = &[
+        Gocomics::IMAGE_URL_PREFIX,
+        "https://assets.amuniversal.com/",
+        "http://assets.amuniversal.com/",
+    ];
+
+    /// Use a different Wayback Machine server, eg. a local stand-in for testing
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Get timestamp and original URL from archived URL
+    ///
+    /// Eg. `https://web.archive.org/web/20010910123456id_/https://...`
+    fn split_archived_url(url: &str) -> Option<(&str, &str)> {
+        let rest = &url[url.find("/web/")? + "/web/".len()..];
+        let (timestamp, original) = rest.split_once('/')?;
+        let timestamp = timestamp.trim_end_matches(|ch: char| !ch.is_ascii_digit());
+        if timestamp.is_empty() || !timestamp.chars().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+        Some((timestamp, original))
+    }
+
+    /// Check that URL is a gocomics.com image asset (possibly already archived), and get
+    /// archived URL of original image
+    fn get_archived_image_url(&self, timestamp: &str, candidate: &str) -> Option<String> {
+        let candidate = candidate.trim();
+        let (timestamp, original) = match Self::split_archived_url(candidate) {
+            Some(archived) => archived,
+            None => (timestamp, candidate),
+        };
+
+        let url = Url::parse(original).ok()?;
+        let id = match url.host_str()? {
+            "featureassets.gocomics.com" => url.path().strip_prefix("/assets/")?,
+            "assets.amuniversal.com" => url.path().strip_prefix('/')?,
+            _ => return None,
+        };
+        if id.is_empty() || !id.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+            return None;
+        }
+
+        let original = format!(
+            "https://{}/{}",
+            url.host_str()?,
+            url.path().trim_start_matches('/')
+        );
+        Some(format!(
+            "{}/web/{}im_/{}",
+            self.base_url, timestamp, original
+        ))
+    }
+}
+
+impl Default for Wayback {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_URL)
+    }
+}
+
+impl Source for Wayback {
+    fn name(&self) -> &str {
+        "wayback"
+    }
+
+    fn lookup(&self, comic: &Comic, date: NaiveDate) -> Lookup {
+        let page_url = format!(
+            "www.gocomics.com/{}/{}",
+            comic.slug,
+            date.format("%Y/%m/%d")
+        );
+        // Nearest successful snapshot to date of comic
+        Lookup::Search(format!(
+            "{}/cdx/search/cdx?url={}&output=json&filter=statuscode:200&closest={}&sort=closest&limit=1",
+            self.base_url,
+            page_url,
+            date.format("%Y%m%d"),
+        ))
+    }
+
+    /// Wayback Machine does not rate limit as strictly as gocomics.com
+    fn use_proxy(&self) -> bool {
+        false
+    }
+
+    fn read_search_results(
+        &self,
+        _comic: &Comic,
+        _date: NaiveDate,
+        body: &str,
+    ) -> Result<Lookup, ExtractError> {
+        // Empty body if no snapshots
+        if body.trim().is_empty() {
+            return Ok(Lookup::Unavailable);
+        }
+        let rows: Vec<Vec<String>> = serde_json::from_str(body)
+            .map_err(|error| ExtractError::MalformedResults(error.to_string()))?;
+
+        // First row is header
+        let mut rows = rows.into_iter();
+        let Some(header) = rows.next() else {
+            return Ok(Lookup::Unavailable);
+        };
+        let Some(row) = rows.next() else {
+            return Ok(Lookup::Unavailable);
+        };
+        let column = |name: &str| {
+            let index = header.iter().position(|column| column == name)?;
+            row.get(index)
+        };
+        let (Some(timestamp), Some(original)) = (column("timestamp"), column("original")) else {
+            return Err(ExtractError::MalformedResults(
+                "Missing `timestamp` or `original` column".to_string(),
+            ));
+        };
+
+        // `id_` returns page without rewritten links
+        Ok(Lookup::Page(format!(
+            "{}/web/{}id_/{}",
+            self.base_url, timestamp, original
+        )))
+    }
+
+    fn find_image_url(&self, page_url: &str, body: &str) -> Result<String, ExtractError> {
+        let Some((timestamp, _)) = Self::split_archived_url(page_url) else {
+            return Err(ExtractError::InvalidUrl(page_url.to_string()));
+        };
+        let candidates = find_candidate_urls(body, Self::ASSET_PREFIXES);
+        if let Some(url) = candidates
+            .iter()
+            .find_map(|candidate| self.get_archived_image_url(timestamp, candidate))
+        {
+            return Ok(url);
+        }
+        match candidates.into_iter().next() {
+            Some(candidate) => Err(ExtractError::InvalidUrl(candidate)),
+            None => Err(ExtractError::LayoutChanged),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::NaiveDate;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::comics::Comic;
+
+/// Gzip-compressed webpages and search results fetched by sources, stored by comic, source, and
+/// date
+///
+/// Layout is `<folder>/<comic>/<source>/YYYY-MM-DD.html.gz` (webpages) and
+/// `<folder>/<comic>/<source>/YYYY-MM-DD.search.gz` (search results)
+#[derive(Debug)]
+pub struct PageArchive {
+    folder: PathBuf,
+    /// Use archived webpages instead of fetching them
+    reparse: bool,
+    /// Only archive webpages which image URL could not be found in
+    failed_only: bool,
+}
+
+/// Kind of response which is archived, as a source may fetch both for one date
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchivedKind {
+    /// Webpage containing image URL, see [crate::api::Lookup::Page]
+    Page,
+    /// Search results, see [crate::api::Lookup::Search]
+    Search,
+}
+
+impl ArchivedKind {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Page => "html.gz",
+            Self::Search => "search.gz",
+        }
+    }
+}
+
+impl PageArchive {
+    pub fn new(folder: impl Into<PathBuf>, reparse: bool) -> Self {
+        Self {
+            folder: folder.into(),
+            reparse,
+            failed_only: false,
+        }
+    }
+
+    /// Archive which only keeps webpages which image URL could not be found in, so they are not
+    /// lost without `--archive-pages`
+    pub fn failed_only(folder: impl Into<PathBuf>) -> Self {
+        Self {
+            failed_only: true,
+            ..Self::new(folder, false)
+        }
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    pub fn is_reparse(&self) -> bool {
+        self.reparse
+    }
+
+    /// Whether webpage should be archived, after finding image URL in it
+    pub fn should_write(&self, extracted: bool) -> bool {
+        // Archived webpages are not written again when re-parsing
+        if self.reparse {
+            return false;
+        }
+        !self.failed_only || !extracted
+    }
+
+    fn get_path(
+        &self,
+        comic: &Comic,
+        source_name: &str,
+        date: NaiveDate,
+        kind: ArchivedKind,
+    ) -> PathBuf {
+        self.folder
+            .join(comic.slug)
+            .join(sanitize_name(source_name))
+            .join(format!("{}.{}", date.format("%Y-%m-%d"), kind.extension()))
+    }
+
+    /// Returns `None` if webpage is not archived
+    pub fn read(
+        &self,
+        comic: &Comic,
+        source_name: &str,
+        date: NaiveDate,
+        kind: ArchivedKind,
+    ) -> io::Result<Option<String>> {
+        let file = match fs::File::open(self.get_path(comic, source_name, date, kind)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let mut body = String::new();
+        GzDecoder::new(file).read_to_string(&mut body)?;
+        Ok(Some(body))
+    }
+
+    /// Replaces existing archived webpage
+    pub fn write(
+        &self,
+        comic: &Comic,
+        source_name: &str,
+        date: NaiveDate,
+        kind: ArchivedKind,
+        body: &str,
+    ) -> io::Result<()> {
+        let path = self.get_path(comic, source_name, date, kind);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut encoder = GzEncoder::new(fs::File::create(path)?, Compression::default());
+        encoder.write_all(body.as_bytes())?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// Source names may be paths or URLs (eg. local sources)
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_') {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -1,123 +1,203 @@
+mod compression;
+mod format;
+mod maintain;
+mod mirror;
+mod writer;
+
 use std::collections::HashMap;
-use std::fs::File;
-use std::{fs, io::Write};
+use std::path::Path;
+use std::time::Duration;
 
+use bytes::Bytes;
 use chrono::NaiveDate;
-use futures::{io, TryFutureExt};
-use reqwest::Client;
+use futures::TryFutureExt;
+use reqwest::{header::CONTENT_ENCODING, Client};
 
-use crate::dates::date_from_filename;
+use crate::api::{Gocomics, Source};
+use crate::colors::*;
 use crate::format_request_error;
-use crate::{colors::*, IMAGE_URL_PREFIX};
+use crate::rate_limit::RateLimiter;
+
+pub use self::compression::Compression;
+pub use self::format::CacheEntry;
+pub(crate) use self::maintain::is_stale_status;
+pub use self::maintain::{
+    cache_file_stats, diff_cache_files, evict_cache_rows, merge_cache_files, read_cache_rows,
+    read_cache_text, refresh_cache_urls, verify_cache_file, CacheDiff, CacheProblem, CacheRows,
+    CacheStats, RefreshReport, YearCoverage,
+};
+use self::mirror::{Mirror, MirrorResult};
+pub use self::writer::{clean_cache_file, CacheWriter};
 
 #[derive(Clone)]
 pub struct DateUrlCached {
     pub date: NaiveDate,
-    pub url: Option<String>,
+    /// Cache row for date, with expanded image URL
+    pub cached: Option<CacheEntry>,
+    /// Index of cache file which provided row, in order of precedence
+    pub cache_layer: usize,
+    /// Cache file which provided row, if multiple cache files are used, for displaying to user
+    pub cache_layer_name: Option<String>,
+}
+
+pub type DateMap = HashMap<NaiveDate, CacheEntry>;
+
+/// Rows of multiple cache files, with index of cache file which provided each row
+pub type LayeredDateMap = HashMap<NaiveDate, (usize, CacheEntry)>;
+
+/// Where cache file was read from, for displaying to user
+pub enum CacheStatus {
+    /// Local cache file
+    Local,
+    /// Remote cache file, which was downloaded
+    Downloaded,
+    /// Local copy of remote cache file, which is up to date
+    NotModified { age: Duration },
+    /// Local copy of remote cache file, as remote was unreachable
+    Fallback { age: Duration, error: String },
 }
 
-type DateMap = HashMap<NaiveDate, String>;
-
-pub async fn fetch_cached_urls(client: &Client, cache_url: &str) -> Result<DateMap, String> {
-    let text = if is_remote_url(cache_url) {
-        fetch_text(client, cache_url)
-            .map_err(|error|
-                format!(
-                    "{RED}{BOLD}Remote cache download unavailable{RESET} - {}.\n{DIM}Trying to fetch {UNDERLINE}{}{RESET}",
-                    cache_url,
-                  format_request_error(  error),
-                ))
-            .await?
+/// Read local cache file, or fetch remote cache file
+///
+/// Remote cache file is mirrored in folder if given, and only downloaded if it has changed
+pub async fn fetch_cached_urls(
+    client: &Client,
+    rate_limiter: &RateLimiter,
+    cache_url: &str,
+    mirror_folder: Option<&Path>,
+) -> Result<(DateMap, CacheStatus), String> {
+    let map_request_error = |error| {
+        format!(
+            "{RED}{BOLD}Remote cache download unavailable{RESET} - {}.\n{DIM}Trying to fetch {UNDERLINE}{}{RESET}",
+            cache_url,
+            format_request_error(error),
+        )
+    };
+
+    let decode = |bytes: &[u8], compression| {
+        compression::decode(bytes, compression)
+            .map_err(|error| format!("Decompressing cache file - {}", error))
+    };
+    // Remote compression is detected by magic bytes, falling back to extension
+    let url_compression = Compression::from_extension(cache_url);
+
+    let (text, status) = if !is_remote_url(cache_url) {
+        let text = compression::read_file(Path::new(cache_url))
+            .map_err(|error| format!("Reading local cache file - {}", error))?;
+        (text, CacheStatus::Local)
+    } else if let Some(mirror_folder) = mirror_folder {
+        let mirror = Mirror::new(mirror_folder, cache_url);
+        match mirror
+            .fetch(client, rate_limiter, cache_url)
+            .await
+            .map_err(map_request_error)?
+        {
+            MirrorResult::Downloaded(bytes) => {
+                (decode(&bytes, url_compression)?, CacheStatus::Downloaded)
+            }
+            MirrorResult::NotModified { bytes, age } => (
+                decode(&bytes, url_compression)?,
+                CacheStatus::NotModified { age },
+            ),
+            MirrorResult::Fallback { bytes, age, error } => (
+                decode(&bytes, url_compression)?,
+                CacheStatus::Fallback {
+                    age,
+                    error: format_request_error(error),
+                },
+            ),
+        }
     } else {
-        fs::read_to_string(cache_url)
-            .map_err(|error| format!("Reading local cache file - {}", error))?
+        let (bytes, content_encoding) = fetch_bytes(client, rate_limiter, cache_url)
+            .map_err(map_request_error)
+            .await?;
+        let compression = match content_encoding {
+            Some(content_encoding) => Compression::from_content_encoding(&content_encoding)?,
+            None => url_compression,
+        };
+        (decode(&bytes, compression)?, CacheStatus::Downloaded)
     };
-    parse_cached_urls(&text).map_err(|_error| "Failed to parse cache file".to_string())
+
+    let dates = parse_cached_urls(&text)
+        .map_err(|error| format!("Failed to parse cache file - {}", error))?;
+    Ok((dates, status))
 }
 
-pub fn is_remote_url(url: &str) -> bool {
-    url.starts_with("http://") || url.starts_with("https://")
+/// Read local copy of remote cache file without network access, and get its age
+///
+/// Returns `None` if remote cache file has not been mirrored
+pub fn read_mirrored_urls(
+    cache_url: &str,
+    mirror_folder: &Path,
+) -> Option<Result<(DateMap, Duration), String>> {
+    let (bytes, age) = Mirror::new(mirror_folder, cache_url).read_local()?;
+    let text = compression::decode(&bytes, Compression::from_extension(cache_url))
+        .map_err(|error| format!("Decompressing cache file - {}", error));
+    Some(text.and_then(|text| {
+        parse_cached_urls(&text)
+            .map(|dates| (dates, age))
+            .map_err(|error| format!("Failed to parse cache file - {}", error))
+    }))
 }
 
-fn parse_cached_urls(file: &str) -> Result<DateMap, ()> {
-    let mut rows = HashMap::new();
-    for line in file.lines() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+/// Combine rows of cache files, given in order of precedence
+///
+/// Each date uses row of first cache file which has it, so earlier files override later files
+pub fn merge_layers(layers: impl IntoIterator<Item = DateMap>) -> LayeredDateMap {
+    let mut merged = LayeredDateMap::new();
+    for (index, layer) in layers.into_iter().enumerate() {
+        for (date, entry) in layer {
+            merged.entry(date).or_insert((index, entry));
         }
-        let (date_string, url) = split_first_word(line).ok_or(())?;
-        let date = date_from_filename(date_string.trim()).ok_or(())?;
-        let url = expand_image_url(url.trim());
-        rows.insert(date, url);
     }
-    Ok(rows)
+    merged
 }
 
-fn split_first_word(string: &str) -> Option<(&str, &str)> {
-    let index = string.find(' ')?;
-    Some(string.split_at(index))
+/// Shorten image URL, for saving to cache file
+///
+/// URLs are always minified as GoComics asset URLs, whichever sources are used, so cache files
+/// can be shared between source chains. Other URLs are saved in full
+pub fn minify_image_url(url: &str) -> &str {
+    Gocomics.minify_image_url(url)
 }
 
-async fn fetch_text(client: &Client, url: &str) -> Result<String, reqwest::Error> {
-    client
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await
+/// Reverse of [minify_image_url], for reading from cache file
+pub fn expand_image_url(minified: &str) -> String {
+    Gocomics.expand_image_url(minified)
 }
 
-fn open_cache_file_to_append(cache_file: &str) -> Result<File, String> {
-    fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(cache_file)
-        .map_err(|error| format!("Opening cache file - {}", error))
-}
-
-pub fn append_cache_file(date: NaiveDate, image_url: &str, cache_file: &str) -> Result<(), String> {
-    let mut file = open_cache_file_to_append(cache_file)?;
-    writeln!(file, "{} {}", date, minify_image_url(image_url))
-        .map_err(|error| format!("Writing to cache file - {}", error))?;
-    Ok(())
+pub fn is_remote_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
 }
 
-fn minify_image_url(url: &str) -> &str {
-    url.strip_prefix(IMAGE_URL_PREFIX).unwrap_or(url)
+/// Parse cache file of any supported version, and expand image URLs
+///
+/// Later rows take precedence over earlier rows for the same date
+fn parse_cached_urls(file: &str) -> Result<DateMap, String> {
+    let rows = format::parse_file(file)?;
+    Ok(rows
+        .into_iter()
+        .map(|(date, mut entry)| {
+            entry.url = expand_image_url(&entry.url);
+            (date, entry)
+        })
+        .collect())
 }
-fn expand_image_url(minified: &str) -> String {
-    if minified.starts_with(IMAGE_URL_PREFIX) {
-        return minified.to_string();
-    }
-    IMAGE_URL_PREFIX.to_string() + minified
-}
-
-pub fn clean_cache_file(cache_file: &str) -> io::Result<()> {
-    let file = fs::read_to_string(cache_file)?;
-    let rows: Vec<_> = file.lines().collect();
-
-    // remove duplicates
-    // keep last instance of each date
-    let mut unique_rows = Vec::new();
-    let mut seen_dates = Vec::new();
-    for row in rows.into_iter().rev() {
-        let Some((date, _)) = split_first_word(row) else {
-            continue;
-        };
-        if seen_dates.contains(&date) {
-            continue;
-        }
-        unique_rows.push(row);
-        seen_dates.push(date);
-    }
 
-    // sort by date (alphabetically)
-    unique_rows.sort();
-
-    // with trailing newline
-    let file = unique_rows.join("\n") + "\n";
-
-    fs::write(cache_file, file)
+/// Fetch file, with value of `Content-Encoding` header
+async fn fetch_bytes(
+    client: &Client,
+    rate_limiter: &RateLimiter,
+    url: &str,
+) -> Result<(Bytes, Option<String>), reqwest::Error> {
+    let response = rate_limiter
+        .send(client.get(url))
+        .await?
+        .error_for_status()?;
+    let content_encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    Ok((response.bytes().await?, content_encoding))
 }
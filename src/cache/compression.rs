@@ -0,0 +1,85 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of a cache file
+///
+/// Compressed files may contain multiple members (gzip) or frames (zstd), so rows can be appended
+/// by appending a compressed batch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect by file extension (`.gz` or `.zst`), ignoring URL query and fragment
+    pub fn from_extension(path: &str) -> Self {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        if path.ends_with(".gz") {
+            Self::Gzip
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Detect by value of `Content-Encoding` header
+    pub fn from_content_encoding(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "identity" => Ok(Self::None),
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("Unsupported content encoding `{}`", value)),
+        }
+    }
+
+    fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(text.as_bytes().to_vec()),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(text.as_bytes())?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(text.as_bytes(), 0),
+        }
+    }
+}
+
+/// Decompress cache file, detected by magic bytes, or by given compression otherwise
+pub fn decode(bytes: &[u8], compression: Compression) -> io::Result<String> {
+    let bytes = match Compression::from_magic(bytes).unwrap_or(compression) {
+        Compression::None => bytes.to_vec(),
+        Compression::Gzip => {
+            let mut decoded = Vec::new();
+            MultiGzDecoder::new(bytes).read_to_end(&mut decoded)?;
+            decoded
+        }
+        Compression::Zstd => zstd::decode_all(bytes)?,
+    };
+    String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Read local cache file, which may be compressed
+pub fn read_file(path: &Path) -> io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    decode(&bytes, Compression::from_extension(&path.to_string_lossy()))
+}
@@ -0,0 +1,276 @@
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+use crate::dates::date_from_filename;
+
+/// Version of cache file written by this version of everygarf
+pub const CURRENT_VERSION: u32 = 2;
+
+const HEADER_PREFIX: &str = "# everygarf cache v";
+
+/// Placeholder for a missing optional value
+const EMPTY_VALUE: &str = "-";
+
+/// Columns written by this version. Files without a header (v1) use the same order, with only
+/// date and URL
+const COLUMNS: [Column; 6] = [
+    Column::Date,
+    Column::Url,
+    Column::Sha256,
+    Column::Size,
+    Column::Dimensions,
+    Column::Source,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Date,
+    Url,
+    Sha256,
+    Size,
+    Dimensions,
+    Source,
+    /// Column from a newer version, which is ignored
+    Unknown,
+}
+
+/// Single row of a cache file
+///
+/// URL is minified, as written in file (see [crate::cache::minify_image_url])
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheEntry {
+    pub url: String,
+    /// Lowercase hex digest of image file, as downloaded
+    pub sha256: Option<String>,
+    /// Bytes of image file, as downloaded
+    pub size: Option<u64>,
+    /// Width and height of image
+    pub dimensions: Option<(u32, u32)>,
+    /// Name of source which image was downloaded from
+    pub source: Option<String>,
+}
+
+/// Parsed row, or reason it is invalid
+pub type RowResult = Result<(NaiveDate, CacheEntry), String>;
+
+/// Order of columns in rows, given by header
+#[derive(Debug)]
+struct Layout {
+    columns: Vec<Column>,
+}
+
+impl CacheEntry {
+    /// Describe a downloaded image file
+    pub fn from_image(
+        url: String,
+        bytes: &[u8],
+        dimensions: (u32, u32),
+        source: Option<&str>,
+    ) -> Self {
+        Self {
+            url,
+            sha256: Some(hash_bytes(bytes)),
+            size: Some(bytes.len() as u64),
+            dimensions: Some(dimensions),
+            source: source.map(|source| source.replace(char::is_whitespace, "_")),
+        }
+    }
+
+    /// Check downloaded image file against size and checksum, if known
+    ///
+    /// Rows from v1 cache files have neither, so always match
+    pub fn verify(&self, bytes: &[u8]) -> Result<(), String> {
+        if let Some(size) = self.size {
+            if size != bytes.len() as u64 {
+                return Err(format!(
+                    "Expected {} bytes, but received {}",
+                    size,
+                    bytes.len()
+                ));
+            }
+        }
+        if let Some(sha256) = &self.sha256 {
+            if *sha256 != hash_bytes(bytes) {
+                return Err("Checksum does not match".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase hex SHA-256 digest
+fn hash_bytes(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Date => "date",
+            Self::Url => "url",
+            Self::Sha256 => "sha256",
+            Self::Size => "size",
+            Self::Dimensions => "dimensions",
+            Self::Source => "source",
+            Self::Unknown => "",
+        }
+    }
+
+    fn from_name(name: &str) -> Self {
+        COLUMNS
+            .into_iter()
+            .find(|column| column.name() == name)
+            .unwrap_or(Self::Unknown)
+    }
+}
+
+impl Layout {
+    /// Parse header line, eg. `# everygarf cache v2: date url sha256 size dimensions source`
+    ///
+    /// Returns `None` if line is not a header (eg. a comment)
+    fn parse_header(line: &str) -> Option<Result<Self, String>> {
+        let rest = line.strip_prefix(HEADER_PREFIX)?;
+        let (version, columns) = rest.split_once(':').unwrap_or((rest, ""));
+        let Ok(version) = version.trim().parse::<u32>() else {
+            return Some(Err(format!("Invalid cache file header `{}`", line)));
+        };
+        if version > CURRENT_VERSION {
+            return Some(Err(format!(
+                "Cache file version {} is not supported (latest is {}). Please update everygarf",
+                version, CURRENT_VERSION,
+            )));
+        }
+
+        let columns: Vec<_> = columns.split_whitespace().map(Column::from_name).collect();
+        if columns.first() != Some(&Column::Date) || columns.get(1) != Some(&Column::Url) {
+            return Some(Err(format!(
+                "Cache file header must start with `date url` columns (`{}`)",
+                line
+            )));
+        }
+        Some(Ok(Self { columns }))
+    }
+
+    fn parse_row(&self, line: &str) -> Result<(NaiveDate, CacheEntry), String> {
+        let invalid = || format!("Invalid cache file row `{}`", line);
+
+        let mut date = None;
+        let mut entry = CacheEntry::default();
+        for (column, value) in self.columns.iter().zip(line.split_whitespace()) {
+            if value == EMPTY_VALUE {
+                continue;
+            }
+            match column {
+                Column::Date => date = date_from_filename(value),
+                Column::Url => entry.url = value.to_string(),
+                Column::Sha256 => entry.sha256 = Some(value.to_lowercase()),
+                Column::Size => entry.size = Some(value.parse().map_err(|_| invalid())?),
+                Column::Dimensions => {
+                    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                    let width = width.parse().map_err(|_| invalid())?;
+                    let height = height.parse().map_err(|_| invalid())?;
+                    entry.dimensions = Some((width, height));
+                }
+                Column::Source => entry.source = Some(value.to_string()),
+                Column::Unknown => (),
+            }
+        }
+
+        match date {
+            Some(date) if !entry.url.is_empty() => Ok((date, entry)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            columns: COLUMNS.to_vec(),
+        }
+    }
+}
+
+/// Parse rows of a cache file, with or without a header
+///
+/// Rows are in order of file, so may contain duplicate dates. Fails on first invalid line
+pub fn parse_file(file: &str) -> Result<Vec<(NaiveDate, CacheEntry)>, String> {
+    parse_lines(file)
+        .into_iter()
+        .map(|(line_number, row)| row.map_err(|error| format!("Line {}: {}", line_number, error)))
+        .collect()
+}
+
+/// Check every header of a cache file is valid and supported, ignoring rows
+///
+/// Rows following an invalid header cannot be parsed correctly
+pub fn check_headers(file: &str) -> Result<(), String> {
+    for (index, line) in file.lines().enumerate() {
+        if let Some(Err(error)) = Layout::parse_header(line.trim()) {
+            return Err(format!("Line {}: {}", index + 1, error));
+        }
+    }
+    Ok(())
+}
+
+/// Parse each row of a cache file, with line numbers (starting at 1), and without failing on
+/// invalid lines
+///
+/// Invalid headers are included as errors, and following rows use the previous header
+pub fn parse_lines(file: &str) -> Vec<(usize, RowResult)> {
+    let mut layout = Layout::default();
+    let mut rows = Vec::new();
+    for (index, line) in file.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('#') {
+            match Layout::parse_header(line) {
+                Some(Ok(header)) => layout = header,
+                Some(Err(error)) => rows.push((line_number, Err(error))),
+                None => (),
+            }
+            continue;
+        }
+        rows.push((line_number, layout.parse_row(line)));
+    }
+    rows
+}
+
+pub fn format_header() -> String {
+    let columns: Vec<_> = COLUMNS.iter().map(Column::name).collect();
+    format!(
+        "{}{}: {}",
+        HEADER_PREFIX,
+        CURRENT_VERSION,
+        columns.join(" ")
+    )
+}
+
+/// Format row, with columns in same order as [format_header]
+pub fn format_row(date: NaiveDate, entry: &CacheEntry) -> String {
+    let or_empty = |value: Option<String>| value.unwrap_or_else(|| EMPTY_VALUE.to_string());
+    let row = [
+        date.to_string(),
+        entry.url.clone(),
+        or_empty(entry.sha256.clone()),
+        or_empty(entry.size.map(|size| size.to_string())),
+        or_empty(
+            entry
+                .dimensions
+                .map(|(width, height)| format!("{}x{}", width, height)),
+        ),
+        or_empty(entry.source.clone()),
+    ];
+    // Trailing empty values are not needed
+    let length = row
+        .iter()
+        .rposition(|value| value != EMPTY_VALUE)
+        .map_or(0, |index| index + 1);
+    row[..length].join(" ")
+}
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::{Datelike, NaiveDate};
+use futures::{stream, StreamExt};
+use reqwest::{Client, StatusCode, Url};
+
+use crate::comics::Comic;
+use crate::dates;
+use crate::rate_limit::RateLimiter;
+
+use super::compression;
+use super::format::{self, CacheEntry};
+use super::writer::{lock_cache_file, replace_cache_file};
+
+/// Rows of a cache file, without duplicate dates
+pub type CacheRows = BTreeMap<NaiveDate, CacheEntry>;
+
+/// Difference between two cache files
+#[derive(Debug, Default)]
+pub struct CacheDiff {
+    /// Dates only in first file
+    pub removed: Vec<(NaiveDate, CacheEntry)>,
+    /// Dates only in second file
+    pub added: Vec<(NaiveDate, CacheEntry)>,
+    /// Dates in both files, with different rows (first, second)
+    pub changed: Vec<(NaiveDate, CacheEntry, CacheEntry)>,
+}
+
+/// Problem found in a cache file by [verify_cache_file]
+#[derive(Debug, PartialEq)]
+pub struct CacheProblem {
+    pub line_number: usize,
+    pub message: String,
+}
+
+/// Coverage of a cache file, over all dates of a comic
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub years: Vec<YearCoverage>,
+    /// Ranges of consecutive dates which are not cached (inclusive)
+    pub gaps: Vec<(NaiveDate, NaiveDate)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct YearCoverage {
+    pub year: i32,
+    pub cached_count: usize,
+    /// Amount of comics published in year, up to latest date
+    pub total_count: usize,
+}
+
+/// Result of checking cached URLs with [refresh_cache_urls]
+#[derive(Debug, Default)]
+pub struct RefreshReport {
+    pub checked_count: usize,
+    /// Dates with URLs which no longer exist, with status of response
+    pub stale: Vec<(NaiveDate, StatusCode)>,
+    /// Dates with URLs which could not be checked, eg. from timeout or server error
+    pub failed: Vec<(NaiveDate, String)>,
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl CacheStats {
+    pub fn cached_count(&self) -> usize {
+        self.years.iter().map(|year| year.cached_count).sum()
+    }
+
+    pub fn total_count(&self) -> usize {
+        self.years.iter().map(|year| year.total_count).sum()
+    }
+}
+
+/// Read local cache file, which may be compressed
+pub fn read_cache_text(path: &str) -> Result<String, String> {
+    compression::read_file(Path::new(path))
+        .map_err(|error| format!("Reading cache file `{path}` - {error}"))
+}
+
+/// Read and parse local cache file, keeping last row for each date
+pub fn read_cache_rows(path: &str) -> Result<CacheRows, String> {
+    let file = read_cache_text(path)?;
+    let rows = format::parse_file(&file)
+        .map_err(|error| format!("Parsing cache file `{path}` - {error}"))?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Merge cache files into one file, which may be one of the input files
+///
+/// Earlier files take precedence over later files for the same date, as with layered caches.
+/// Returns amount of rows written
+pub fn merge_cache_files(paths: &[String], output: &str) -> Result<usize, String> {
+    let mut merged = CacheRows::new();
+    for path in paths {
+        for (date, entry) in read_cache_rows(path)? {
+            merged.entry(date).or_insert(entry);
+        }
+    }
+    let output_path = Path::new(output);
+    lock_cache_file(output_path)
+        .and_then(|_lock| replace_cache_file(output_path, &merged))
+        .map_err(|error| format!("Writing cache file `{output}` - {error}"))?;
+    Ok(merged.len())
+}
+
+pub fn diff_cache_files(first: &str, second: &str) -> Result<CacheDiff, String> {
+    let first = read_cache_rows(first)?;
+    let mut second = read_cache_rows(second)?;
+
+    let mut diff = CacheDiff::default();
+    for (date, first_entry) in first {
+        match second.remove(&date) {
+            None => diff.removed.push((date, first_entry)),
+            Some(second_entry) if second_entry != first_entry => {
+                diff.changed.push((date, first_entry, second_entry))
+            }
+            Some(_) => (),
+        }
+    }
+    diff.added = second.into_iter().collect();
+    Ok(diff)
+}
+
+/// Find invalid rows, duplicate dates, malformed URLs, and dates which the comic was not
+/// published on
+///
+/// URLs are expanded as they would be when downloading
+pub fn verify_cache_file(file: &str, comic: &Comic) -> Vec<CacheProblem> {
+    let latest_date = dates::latest();
+    let mut seen_dates = HashMap::new();
+    let mut problems = Vec::new();
+    let mut report = |line_number, message| {
+        problems.push(CacheProblem {
+            line_number,
+            message,
+        })
+    };
+
+    for (line_number, row) in format::parse_lines(file) {
+        let (date, entry) = match row {
+            Ok(row) => row,
+            Err(error) => {
+                report(line_number, error);
+                continue;
+            }
+        };
+
+        if let Some(first_line_number) = seen_dates.insert(date, line_number) {
+            report(
+                line_number,
+                format!("Duplicate date {date} (also on line {first_line_number})"),
+            );
+        }
+        if date < comic.first_date || date > latest_date {
+            report(
+                line_number,
+                format!(
+                    "Date {date} is outside of valid range ({} to {latest_date})",
+                    comic.first_date,
+                ),
+            );
+        }
+        let url = super::expand_image_url(&entry.url);
+        if !Url::parse(&url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+            report(line_number, format!("Malformed image URL `{url}`"));
+        }
+    }
+    problems
+}
+
+/// Count cached dates per year, and find uncached dates, from first comic to latest comic
+pub fn cache_file_stats(rows: &CacheRows, comic: &Comic) -> CacheStats {
+    let mut stats = CacheStats::default();
+    let mut gap_start = None;
+    let all_dates = dates::get_dates_between(comic.first_date, dates::latest());
+
+    for date in &all_dates {
+        if stats
+            .years
+            .last()
+            .is_none_or(|year| year.year != date.year())
+        {
+            stats.years.push(YearCoverage {
+                year: date.year(),
+                cached_count: 0,
+                total_count: 0,
+            });
+        }
+        let year = stats.years.last_mut().expect("year was just added");
+        year.total_count += 1;
+
+        if !rows.contains_key(date) {
+            gap_start.get_or_insert(*date);
+            continue;
+        }
+        year.cached_count += 1;
+        if let Some(gap_start) = gap_start.take() {
+            stats
+                .gaps
+                .push((gap_start, *date - chrono::Duration::days(1)));
+        }
+    }
+    if let (Some(gap_start), Some(last_date)) = (gap_start, all_dates.last()) {
+        stats.gaps.push((gap_start, *last_date));
+    }
+    stats
+}
+
+/// Check cached URLs exist, with `HEAD` requests, up to job count at a time
+///
+/// URLs are expanded as they would be when downloading, and requests are sent through rate
+/// limiter, with the same client (and forward proxy) as image downloads
+pub async fn refresh_cache_urls(
+    client: &Client,
+    rate_limiter: &RateLimiter,
+    rows: &CacheRows,
+    job_count: usize,
+) -> RefreshReport {
+    let checks = rows.iter().map(|(date, entry)| {
+        let url = super::expand_image_url(&entry.url);
+        async move { (*date, check_url(client, rate_limiter, &url).await) }
+    });
+    let mut results = stream::iter(checks).buffer_unordered(job_count.max(1));
+
+    let mut report = RefreshReport::default();
+    while let Some((date, result)) = results.next().await {
+        report.checked_count += 1;
+        match result {
+            Ok(status) if is_stale_status(status) => report.stale.push((date, status)),
+            Ok(status) if status.is_success() => (),
+            Ok(status) => report.failed.push((date, status.to_string())),
+            Err(error) => report.failed.push((date, error.to_string())),
+        }
+    }
+    report.stale.sort();
+    report.failed.sort();
+    report
+}
+
+/// Some image hosts do not allow `HEAD` requests, so `GET` is used as a fallback
+async fn check_url(
+    client: &Client,
+    rate_limiter: &RateLimiter,
+    url: &str,
+) -> reqwest::Result<StatusCode> {
+    let status = rate_limiter.send(client.head(url)).await?.status();
+    if status != StatusCode::METHOD_NOT_ALLOWED {
+        return Ok(status);
+    }
+    Ok(rate_limiter.send(client.get(url)).await?.status())
+}
+
+/// Only `404 Not Found` and `410 Gone` mean an image URL is stale
+///
+/// Other client errors (eg. `403 Forbidden` from a firewall blocking bots) may be temporary, or
+/// apply to every URL of a host
+pub(crate) fn is_stale_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE)
+}
+
+/// Remove rows from cache file, unless they have changed since they were read
+///
+/// Returns amount of rows removed
+pub fn evict_cache_rows(path: &str, stale_rows: &CacheRows) -> Result<usize, String> {
+    let file_path = Path::new(path);
+    let _lock = lock_cache_file(file_path)
+        .map_err(|error| format!("Locking cache file `{path}` - {error}"))?;
+    let mut rows = read_cache_rows(path)?;
+    let old_count = rows.len();
+    rows.retain(|date, entry| stale_rows.get(date) != Some(entry));
+    replace_cache_file(file_path, &rows)
+        .map_err(|error| format!("Writing cache file `{path}` - {error}"))?;
+    Ok(old_count - rows.len())
+}
@@ -0,0 +1,185 @@
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::rate_limit::RateLimiter;
+
+/// Local copy of a remote cache file, revalidated with `ETag` and `Last-Modified`
+///
+/// Stored as `<folder>/<name>` (cache file) and `<folder>/<name>.meta` (validators)
+pub struct Mirror {
+    path: PathBuf,
+    meta_path: PathBuf,
+}
+
+/// How cache file was read from a mirrored remote URL
+///
+/// File is stored as it was downloaded, so may be compressed
+pub enum MirrorResult {
+    /// Remote file changed, and was downloaded
+    Downloaded(Vec<u8>),
+    /// Remote file has not changed since local copy was downloaded
+    NotModified { bytes: Vec<u8>, age: Duration },
+    /// Remote file could not be fetched, so local copy was used
+    Fallback {
+        bytes: Vec<u8>,
+        age: Duration,
+        error: reqwest::Error,
+    },
+}
+
+/// Validators and download time of local copy
+#[derive(Default)]
+struct Meta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since unix epoch
+    downloaded_at: u64,
+}
+
+impl Mirror {
+    pub fn new(folder: &Path, url: &str) -> Self {
+        let name: String = url
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect();
+        Self {
+            path: folder.join(&name),
+            meta_path: folder.join(name + ".meta"),
+        }
+    }
+
+    /// Read local copy without revalidating, and get its age
+    pub fn read_local(&self) -> Option<(Vec<u8>, Duration)> {
+        let bytes = fs::read(&self.path).ok()?;
+        Some((bytes, self.read_meta().age()))
+    }
+
+    /// Fetch remote file if it has changed since local copy was downloaded
+    ///
+    /// Falls back to local copy if remote is unreachable
+    pub async fn fetch(
+        &self,
+        client: &Client,
+        rate_limiter: &RateLimiter,
+        url: &str,
+    ) -> Result<MirrorResult, reqwest::Error> {
+        let local = self.read_local();
+        let meta = self.read_meta();
+
+        let mut request = client.get(url);
+        // Only revalidate if local copy exists
+        if local.is_some() {
+            if let Some(etag) = &meta.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &meta.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = match rate_limiter
+            .send(request)
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => response,
+            Err(error) => {
+                return match local {
+                    Some((bytes, age)) => Ok(MirrorResult::Fallback { bytes, age, error }),
+                    None => Err(error),
+                }
+            }
+        };
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some((bytes, age)) = local {
+                return Ok(MirrorResult::NotModified { bytes, age });
+            }
+        }
+
+        let get_header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let new_meta = Meta {
+            etag: get_header(ETAG),
+            last_modified: get_header(LAST_MODIFIED),
+            downloaded_at: now_seconds(),
+        };
+
+        let bytes = match response.bytes().await {
+            Ok(bytes) => bytes.to_vec(),
+            Err(error) => {
+                return match local {
+                    Some((bytes, age)) => Ok(MirrorResult::Fallback { bytes, age, error }),
+                    None => Err(error),
+                }
+            }
+        };
+
+        // Mirror is only an optimization, so failing to write it does not fail download
+        let _ = self.write(&bytes, &new_meta);
+        Ok(MirrorResult::Downloaded(bytes))
+    }
+
+    fn read_meta(&self) -> Meta {
+        let Ok(file) = fs::read_to_string(&self.meta_path) else {
+            return Meta::default();
+        };
+        let mut meta = Meta::default();
+        for line in file.lines() {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "etag" => meta.etag = Some(value.to_string()),
+                "last-modified" => meta.last_modified = Some(value.to_string()),
+                "downloaded" => meta.downloaded_at = value.parse().unwrap_or_default(),
+                _ => (),
+            }
+        }
+        meta
+    }
+
+    /// Write to temporary files first, so an interrupted write does not leave a broken copy
+    fn write(&self, bytes: &[u8], meta: &Meta) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut meta_file = format!("downloaded: {}\n", meta.downloaded_at);
+        if let Some(etag) = &meta.etag {
+            meta_file += &format!("etag: {}\n", etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            meta_file += &format!("last-modified: {}\n", last_modified);
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        let temp_meta_path = self.meta_path.with_extension("meta.tmp");
+        fs::write(&temp_path, bytes)?;
+        fs::write(&temp_meta_path, meta_file)?;
+        fs::rename(temp_path, &self.path)?;
+        fs::rename(temp_meta_path, &self.meta_path)?;
+        Ok(())
+    }
+}
+
+impl Meta {
+    fn age(&self) -> Duration {
+        Duration::from_secs(now_seconds().saturating_sub(self.downloaded_at))
+    }
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use chrono::NaiveDate;
+
+use crate::colors::*;
+
+use super::compression::{self, Compression};
+use super::format::{self, CacheEntry};
+
+type Row = (NaiveDate, CacheEntry);
+
+/// Single writer of a cache file, shared between all download jobs
+///
+/// Rows are sent over a channel to a writer thread, which appends them to the file while holding
+/// an advisory lock, so other processes using the same cache file do not interleave writes
+pub struct CacheWriter {
+    path: PathBuf,
+    sender: Mutex<Option<mpsc::Sender<Row>>>,
+    thread: Mutex<Option<JoinHandle<Result<(), String>>>>,
+    stopped: AtomicBool,
+}
+
+impl CacheWriter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let (sender, receiver) = mpsc::channel();
+        let thread = {
+            let path = path.clone();
+            thread::spawn(move || write_rows(&path, receiver))
+        };
+        Self {
+            path,
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue row to be appended, with image URL minified
+    ///
+    /// If writer has stopped, from an error or from [CacheWriter::finish], row is dropped and a
+    /// warning is printed once. Error of writer thread is returned by [CacheWriter::finish]
+    pub fn append(&self, date: NaiveDate, entry: &CacheEntry) {
+        let entry = CacheEntry {
+            url: super::minify_image_url(&entry.url).to_string(),
+            ..entry.clone()
+        };
+        let sent = match &*self.sender.lock().expect("lock cache writer") {
+            Some(sender) => sender.send((date, entry)).is_ok(),
+            None => false,
+        };
+        if !sent && !self.stopped.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "{YELLOW}[warning]{RESET} Cache file writer has stopped, so no more rows will be saved to `{}`",
+                self.path.display(),
+            );
+        }
+    }
+
+    /// Write all queued rows, then remove duplicate dates and sort rows
+    ///
+    /// Calling again has no effect, but returns error of writer thread only once
+    pub fn finish(&self) -> Result<(), String> {
+        // Writer thread stops when channel is closed
+        self.sender.lock().expect("lock cache writer").take();
+        let thread = self.thread.lock().expect("lock cache writer").take();
+        let Some(thread) = thread else {
+            return Ok(());
+        };
+        thread
+            .join()
+            .map_err(|_| "Cache file writer panicked".to_string())??;
+        // Nothing was written
+        if !self.path.exists() {
+            return Ok(());
+        }
+        clean_cache_file(&self.path).map_err(|error| format!("Cleaning cache file - {}", error))
+    }
+}
+
+/// Append rows in batches until channel is closed
+///
+/// File is reopened for each batch, in case another process has replaced it
+fn write_rows(path: &Path, receiver: mpsc::Receiver<Row>) -> Result<(), String> {
+    while let Ok(row) = receiver.recv() {
+        let mut rows = vec![row];
+        rows.extend(receiver.try_iter());
+
+        let _lock =
+            lock_cache_file(path).map_err(|error| format!("Locking cache file - {}", error))?;
+        append_rows(path, &rows).map_err(|error| format!("Writing to cache file - {}", error))?;
+    }
+    Ok(())
+}
+
+/// Append rows to cache file, writing header first if file is new
+///
+/// Rows of compressed files are appended as a new compressed member or frame
+fn append_rows(path: &Path, rows: &[Row]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut text = String::new();
+    if file.metadata()?.len() == 0 {
+        text += &format::format_header();
+        text += "\n";
+    }
+    for (date, entry) in rows {
+        text += &format::format_row(*date, entry);
+        text += "\n";
+    }
+    let bytes = compression_of(path).encode(&text)?;
+    // Single write, so a crash cannot leave a partial batch before a complete one
+    file.write_all(&bytes)?;
+    file.flush()
+}
+
+/// Take advisory lock of cache file, which is released when returned file is dropped
+///
+/// A separate `<file>.lock` file is used, as cache file is replaced when it is rewritten. It is
+/// never removed, as every process writing the cache file (even as another user) must lock the
+/// same file
+pub fn lock_cache_file(path: &Path) -> io::Result<File> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Remove duplicate dates and sort rows, and upgrade file to latest version
+///
+/// Invalid rows are removed, with a warning. Fails without changing file if any header is
+/// invalid or unsupported, eg. from a newer version
+pub fn clean_cache_file(path: &Path) -> io::Result<()> {
+    let _lock = lock_cache_file(path)?;
+    let file = compression::read_file(path)?;
+    format::check_headers(&file)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    // remove duplicates
+    // keep last instance of each date
+    let mut unique_rows = BTreeMap::new();
+    for (line_number, row) in format::parse_lines(&file) {
+        match row {
+            Ok((date, entry)) => {
+                unique_rows.insert(date, entry);
+            }
+            Err(error) => eprintln!(
+                "{YELLOW}[warning]{RESET} Removing invalid row of cache file {BOLD}{}{RESET} {DIM}(line {}){RESET} - {}",
+                path.display(),
+                line_number,
+                error,
+            ),
+        }
+    }
+
+    replace_cache_file(path, &unique_rows)
+}
+
+/// Write sorted rows to cache file, with header, replacing existing file
+///
+/// Written to a temporary file first, so existing file is never partially written. Cache file
+/// should be locked
+pub fn replace_cache_file(
+    path: &Path,
+    unique_rows: &BTreeMap<NaiveDate, CacheEntry>,
+) -> io::Result<()> {
+    let mut lines = vec![format::format_header()];
+    lines.extend(
+        unique_rows
+            .iter()
+            .map(|(date, entry)| format::format_row(*date, entry)),
+    );
+
+    // with trailing newline
+    let file = lines.join("\n") + "\n";
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".tmp-{}", process::id()));
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&compression_of(path).encode(&file)?)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+    })
+}
+
+/// Compression of cache file to write, by extension
+fn compression_of(path: &Path) -> Compression {
+    Compression::from_extension(&path.to_string_lossy())
+}
@@ -0,0 +1,185 @@
+use reqwest::Client;
+
+use everygarf::{
+    cache::{self, CacheEntry},
+    colors::*,
+    comics::Comic,
+    fatal_error,
+    rate_limit::RateLimiter,
+    Error,
+};
+
+use crate::args::CacheCommand;
+
+/// Settings from main arguments, used by `cache` subcommands
+pub struct Options<'a> {
+    pub comic: &'a Comic,
+    pub client: &'a Client,
+    pub rate_limiter: &'a RateLimiter,
+    pub job_count: usize,
+    pub notify_on_fail: bool,
+}
+
+/// Run `cache` subcommand, exiting with an error if it fails
+pub async fn run(command: &CacheCommand, options: Options<'_>) {
+    let Options {
+        comic,
+        client,
+        rate_limiter,
+        job_count,
+        notify_on_fail,
+    } = options;
+    let fail = |error: String| -> ! { fatal_error(Error::CacheCommand, error, notify_on_fail) };
+
+    println!();
+    match command {
+        CacheCommand::Merge { files, output } => {
+            let count = cache::merge_cache_files(files, output).unwrap_or_else(|error| fail(error));
+            println!(
+                "{GREEN}{BOLD}Merged{RESET} {} cache files into {UNDERLINE}{}{RESET} {DIM}({} dates){RESET}",
+                files.len(),
+                output,
+                count,
+            );
+        }
+
+        CacheCommand::Diff { old, new } => {
+            let diff = cache::diff_cache_files(old, new).unwrap_or_else(|error| fail(error));
+            for (date, entry) in &diff.removed {
+                println!("{RED}- {BOLD}{}{RESET}{RED} {}{RESET}", date, entry.url);
+            }
+            for (date, entry) in &diff.added {
+                println!("{GREEN}+ {BOLD}{}{RESET}{GREEN} {}{RESET}", date, entry.url);
+            }
+            for (date, old_entry, new_entry) in &diff.changed {
+                println!(
+                    "{YELLOW}~ {BOLD}{}{RESET}{YELLOW} {}{RESET}",
+                    date,
+                    describe_change(old_entry, new_entry),
+                );
+            }
+            if diff.is_empty() {
+                println!("{GREEN}{BOLD}Cache files are identical{RESET}");
+            } else {
+                println!();
+                println!(
+                    " {DIM}•{RESET} Removed: {BOLD}{}{RESET}, added: {BOLD}{}{RESET}, changed: {BOLD}{}{RESET}",
+                    diff.removed.len(),
+                    diff.added.len(),
+                    diff.changed.len(),
+                );
+            }
+        }
+
+        CacheCommand::Verify { file } => {
+            let text = cache::read_cache_text(file).unwrap_or_else(|error| fail(error));
+            let problems = cache::verify_cache_file(&text, comic);
+            for problem in &problems {
+                println!(
+                    " {DIM}•{RESET} {BOLD}Line {}:{RESET} {}",
+                    problem.line_number, problem.message
+                );
+            }
+            if !problems.is_empty() {
+                println!();
+                fail(format!(
+                    "Found {} problems in cache file `{}`",
+                    problems.len(),
+                    file
+                ));
+            }
+            println!(
+                "{GREEN}{BOLD}No problems found{RESET} in {UNDERLINE}{}{RESET}",
+                file
+            );
+        }
+
+        CacheCommand::Stats { file } => {
+            let rows = cache::read_cache_rows(file).unwrap_or_else(|error| fail(error));
+            let stats = cache::cache_file_stats(&rows, comic);
+            println!("{BOLD}Coverage{RESET} of {} comics:", comic.name);
+            for year in &stats.years {
+                println!(
+                    " {DIM}•{RESET} {}: {BOLD}{}{RESET}/{} {DIM}({}%){RESET}",
+                    year.year,
+                    year.cached_count,
+                    year.total_count,
+                    year.cached_count * 100 / year.total_count,
+                );
+            }
+            println!(
+                " {DIM}•{RESET} Total: {BOLD}{}{RESET}/{} {DIM}({} rows outside of range){RESET}",
+                stats.cached_count(),
+                stats.total_count(),
+                rows.len() - stats.cached_count(),
+            );
+            if !stats.gaps.is_empty() {
+                println!("{BOLD}Missing dates:{RESET}");
+                for (start, end) in &stats.gaps {
+                    if start == end {
+                        println!(" {DIM}•{RESET} {}", start);
+                    } else {
+                        let day_count = (*end - *start).num_days() + 1;
+                        println!(
+                            " {DIM}•{RESET} {} to {} {DIM}({} days){RESET}",
+                            start, end, day_count
+                        );
+                    }
+                }
+            }
+        }
+
+        CacheCommand::Refresh { file, evict } => {
+            let rows = cache::read_cache_rows(file).unwrap_or_else(|error| fail(error));
+            println!(
+                "Checking {BOLD}{}{RESET} cached URLs using {BOLD}{}{RESET} concurrent jobs...",
+                rows.len(),
+                job_count,
+            );
+            let report = cache::refresh_cache_urls(client, rate_limiter, &rows, job_count).await;
+            for (date, status) in &report.stale {
+                println!(
+                    " {DIM}•{RESET} {BOLD}{}{RESET} {YELLOW}Stale{RESET} {DIM}({}) {}{RESET}",
+                    date, status, rows[date].url,
+                );
+            }
+            for (date, error) in &report.failed {
+                println!(
+                    " {DIM}•{RESET} {BOLD}{}{RESET} {RED}Failed{RESET} {DIM}- {}{RESET}",
+                    date, error,
+                );
+            }
+            println!();
+            println!(
+                " {DIM}•{RESET} Checked: {BOLD}{}{RESET}, stale: {BOLD}{}{RESET}, failed: {BOLD}{}{RESET}",
+                report.checked_count,
+                report.stale.len(),
+                report.failed.len(),
+            );
+            if *evict && !report.stale.is_empty() {
+                let stale_rows: cache::CacheRows = report
+                    .stale
+                    .iter()
+                    .map(|(date, _)| (*date, rows[date].clone()))
+                    .collect();
+                let count =
+                    cache::evict_cache_rows(file, &stale_rows).unwrap_or_else(|error| fail(error));
+                println!(
+                    " {DIM}•{RESET} Removed {BOLD}{}{RESET} stale URLs from {UNDERLINE}{}{RESET}",
+                    count, file,
+                );
+            } else if !report.stale.is_empty() {
+                println!("{CYAN}Note: {DIM}Run with {BOLD}--evict{RESET}{CYAN}{DIM} to remove stale URLs from cache file{RESET}");
+            }
+        }
+    }
+    println!();
+}
+
+fn describe_change(old: &CacheEntry, new: &CacheEntry) -> String {
+    if old.url != new.url {
+        format!("{} -> {}", old.url, new.url)
+    } else {
+        format!("{} {DIM}(details changed){RESET}", new.url)
+    }
+}
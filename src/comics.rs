@@ -0,0 +1,69 @@
+use chrono::NaiveDate;
+
+/// A comic strip which can be downloaded, identified by its gocomics.com slug
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Comic {
+    /// Name used in page URLs, and to select comic with `--comic`
+    pub slug: &'static str,
+    /// Human-readable name
+    pub name: &'static str,
+    /// Date of first comic published
+    pub first_date: NaiveDate,
+    /// Default folder name, inside user pictures directory
+    pub folder_name: &'static str,
+    /// Default remote cache file, if one is hosted for this comic
+    pub cache_url: Option<&'static str>,
+}
+
+pub const DEFAULT_COMIC: &str = "garfield";
+
+pub const GARFIELD: Comic = Comic {
+    slug: "garfield",
+    name: "Garfield",
+    first_date: static_date(1978, 6, 19),
+    folder_name: "garfield",
+    cache_url: Some(crate::CACHE_DEFAULT),
+};
+
+pub const US_ACRES: Comic = Comic {
+    slug: "us-acres",
+    name: "U.S. Acres",
+    first_date: static_date(1986, 3, 3),
+    folder_name: "us-acres",
+    cache_url: None,
+};
+
+pub const GARFIELD_CLASSICS: Comic = Comic {
+    slug: "garfield-classics",
+    name: "Garfield Classics",
+    first_date: static_date(2016, 6, 19),
+    folder_name: "garfield-classics",
+    cache_url: None,
+};
+
+pub const GARFIELD_SPANISH: Comic = Comic {
+    slug: "garfield-spanish",
+    name: "Garfield en Español",
+    first_date: static_date(1999, 12, 6),
+    folder_name: "garfield-spanish",
+    cache_url: None,
+};
+
+/// All comics which can be selected with `--comic`
+pub const ALL: &[Comic] = &[GARFIELD, US_ACRES, GARFIELD_CLASSICS, GARFIELD_SPANISH];
+
+pub fn get(slug: &str) -> Option<&'static Comic> {
+    ALL.iter()
+        .find(|comic| comic.slug.eq_ignore_ascii_case(slug))
+}
+
+pub fn slugs() -> impl Iterator<Item = &'static str> {
+    ALL.iter().map(|comic| comic.slug)
+}
+
+const fn static_date(year: i32, month: u32, day: u32) -> NaiveDate {
+    match NaiveDate::from_ymd_opt(year, month, day) {
+        Some(date) => date,
+        None => panic!("Static date failed to parse. This error should never occur."),
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Chooses how many downloads to run at once (AIMD)
+///
+/// Concurrency grows while requests are succeeding with healthy latency, and is halved when
+/// requests time out or are rate limited
+#[derive(Debug)]
+pub struct ConcurrencyController {
+    max: usize,
+    state: Mutex<ControllerState>,
+}
+
+#[derive(Debug)]
+struct ControllerState {
+    limit: f64,
+    peak: f64,
+    /// Grow quickly until first backoff
+    is_slow_start: bool,
+    /// Lowest latency of a successful attempt, as baseline for healthy latency
+    min_latency: Option<Duration>,
+    last_backoff: Option<Instant>,
+}
+
+impl ConcurrencyController {
+    const INITIAL: usize = 4;
+    /// Latency above baseline multiplied by this is treated as congestion
+    const LATENCY_TOLERANCE: u32 = 3;
+    /// Failures within this duration of a backoff are caused by the same congestion
+    const BACKOFF_COOLDOWN: Duration = Duration::from_secs(2);
+
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        let initial = Self::INITIAL.min(max) as f64;
+        Self {
+            max,
+            state: Mutex::new(ControllerState {
+                limit: initial,
+                peak: initial,
+                is_slow_start: true,
+                min_latency: None,
+                last_backoff: None,
+            }),
+        }
+    }
+
+    /// Current amount of downloads which may be in flight
+    pub fn limit(&self) -> usize {
+        self.lock_state().limit as usize
+    }
+
+    /// Highest limit which was reached
+    pub fn peak(&self) -> usize {
+        self.lock_state().peak as usize
+    }
+
+    /// Record successful attempt
+    pub fn record_success(&self, latency: Duration) {
+        let mut state = self.lock_state();
+        let min_latency = *state.min_latency.get_or_insert(latency);
+        if latency < min_latency {
+            state.min_latency = Some(latency);
+        }
+        // Slow, but not failing: hold concurrency
+        if latency > min_latency * Self::LATENCY_TOLERANCE {
+            return;
+        }
+
+        let increase = if state.is_slow_start {
+            1.0
+        } else {
+            1.0 / state.limit
+        };
+        state.limit = (state.limit + increase).min(self.max as f64);
+        state.peak = state.peak.max(state.limit);
+    }
+
+    /// Record attempt which timed out, or was rate limited by server
+    pub fn record_overload(&self) {
+        let mut state = self.lock_state();
+        let now = Instant::now();
+        if state
+            .last_backoff
+            .is_some_and(|last_backoff| now.duration_since(last_backoff) < Self::BACKOFF_COOLDOWN)
+        {
+            return;
+        }
+        state.is_slow_start = false;
+        state.limit = (state.limit / 2.0).max(1.0);
+        state.last_backoff = Some(now);
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, ControllerState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
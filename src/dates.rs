@@ -1,10 +1,5 @@
 use chrono::{Duration, NaiveDate, NaiveTime, Utc};
 
-pub fn first() -> NaiveDate {
-    NaiveDate::from_ymd_opt(1978, 6, 19)
-        .expect("Static date failed to parse. This error should never occur.")
-}
-
 pub fn latest() -> NaiveDate {
     let now = Utc::now();
 
@@ -1,19 +1,28 @@
-use bytes::Bytes;
+mod error;
+
+use bytes::{Bytes, BytesMut};
 use chrono::{Datelike, NaiveDate};
 use image::DynamicImage;
-use reqwest::Client;
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder, Url};
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
+
+use self::error::{FetchError, RequestFailure};
 
-use crate::api::Api;
-use crate::cache;
+use crate::api::{Api, Lookup, Source, SourceStep};
+use crate::archive::ArchivedKind;
+use crate::cache::{self, CacheEntry, CacheWriter};
 use crate::colors::*;
-use crate::format_request_error;
+use crate::concurrency::ConcurrencyController;
+use crate::proxy::{ProxyPool, ProxyTicket};
+use crate::rate_limit::RateLimiter;
 use crate::DateUrlCached;
 use crate::SingleDownloadOptions;
 use crate::PROGRESS_COUNT;
 
-fn print_step(date: NaiveDate, job_id: usize, step: u32, total_count: usize) {
+fn print_step(date: NaiveDate, job_id: usize, step: u32, total_count: usize, source_name: &str) {
     let alt = if step < 2 { CYAN } else { "" };
     let icon = if step == 3 { "✓" } else { " " };
     let step = format!(
@@ -24,7 +33,7 @@ fn print_step(date: NaiveDate, job_id: usize, step: u32, total_count: usize) {
     let progress = unsafe { PROGRESS_COUNT } as usize * 100 / total_count;
 
     println!(
-        "    {BOLD}{date}{RESET}  {DIM}#{job_id:02}{RESET}  {CYAN}{progress:-2}%{RESET}  {BLUE}{alt}[{step}{BLUE}{alt}]{RESET}  {GREEN}{icon}{RESET}"
+        "    {BOLD}{date}{RESET}  {DIM}#{job_id:02}{RESET}  {CYAN}{progress:-2}%{RESET}  {BLUE}{alt}[{step}{BLUE}{alt}]{RESET}  {GREEN}{icon}{RESET}  {DIM}{source_name}{RESET}"
     );
 }
 
@@ -35,13 +44,15 @@ pub async fn download_image<'a>(
     job_id: usize,
     total_count: usize,
     download_options: SingleDownloadOptions<'a>,
-) -> Result<(), String> {
+    concurrency: &ConcurrencyController,
+) -> Result<&'a str, DownloadError> {
     let SingleDownloadOptions {
         attempt_count,
         api,
-        cache_file,
+        cache_writer,
         image_format,
         save_as_tree,
+        retry,
     } = download_options;
     let date = date_cached.date;
 
@@ -52,10 +63,10 @@ pub async fn download_image<'a>(
                 month_dir.join(day)
             }
             Err(error) => {
-                return Err(format!(
+                return Err(DownloadError::Failed(format!(
                     "{} Failed to create parent directory - {error}",
                     date_cached.date,
-                ))
+                )))
             }
         }
     } else {
@@ -64,28 +75,80 @@ pub async fn download_image<'a>(
     };
 
     for attempt_no in 1..=attempt_count {
-        let result = fetch_image(client, &date_cached, job_id, total_count, api, cache_file).await;
+        let start_time = Instant::now();
+        let result =
+            fetch_image(client, &date_cached, job_id, total_count, api, cache_writer).await;
         match result {
-            Ok(image) => {
+            Ok((image, source_name)) => {
+                concurrency.record_success(start_time.elapsed());
                 if let Err(error) = image.save(filepath) {
-                    return Err(format!("{} Failed to save image file - {error}", date,));
+                    return Err(DownloadError::Failed(format!(
+                        "{} Failed to save image file - {error}",
+                        date,
+                    )));
                 }
                 unsafe { PROGRESS_COUNT += 1 }
-                break;
+                return Ok(source_name);
             }
             Err(error) => {
+                let error = match error {
+                    AttemptError::Unavailable {
+                        reason,
+                        extraction_failed,
+                    } => {
+                        unsafe { PROGRESS_COUNT += 1 }
+                        return Err(DownloadError::Skipped {
+                            reason,
+                            extraction_failed,
+                        });
+                    }
+                    AttemptError::Failed(error) => error,
+                };
+                let overload = error.get_overload();
+                if overload.is_some() {
+                    concurrency.record_overload();
+                }
+                let retry_after = overload.and_then(RequestFailure::retry_after);
                 eprintln!("{YELLOW}[warning] {DIM}[Attempt {attempt_no}]{RESET} {BOLD}{}{RESET} {DIM}#{job_id}{RESET} Failed: {error}", date);
                 if attempt_no >= attempt_count {
-                    return Err(format!(
+                    return Err(DownloadError::Failed(format!(
                         "{RESET}{BOLD}{}{RESET} Failed after {BOLD}{attempt_count}{RESET} attempts: {error}",
                         date,
-                    ));
+                    )));
                 }
+                tokio::time::sleep(retry.get_delay(attempt_no, retry_after)).await;
             }
         }
     }
 
-    Ok(())
+    Err(DownloadError::Failed(format!(
+        "{} No download attempts allowed",
+        date
+    )))
+}
+
+/// Reason an image could not be downloaded
+pub enum DownloadError {
+    /// Image is not available from any source, so date was skipped
+    Skipped {
+        reason: String,
+        /// Whether any source failed to find image URL, or to decode image
+        extraction_failed: bool,
+    },
+    /// All attempts failed, or image could not be saved
+    Failed(String),
+}
+
+/// Reason a single download attempt failed
+enum AttemptError {
+    /// All sources failed permanently
+    Unavailable {
+        reason: String,
+        /// See [FetchError::is_extraction_failure]
+        extraction_failed: bool,
+    },
+    /// Possibly temporary failure, so retry whole attempt
+    Failed(FetchError),
 }
 
 fn create_month_dir(parent: &Path, date: NaiveDate) -> std::io::Result<PathBuf> {
@@ -117,70 +180,322 @@ async fn fetch_image<'a>(
     job_id: usize,
     total_count: usize,
     api: Api<'a>,
-    cache_file: Option<&str>,
-) -> Result<DynamicImage, String> {
-    let image_url = match &date_cached.url {
-        Some(url) => url.to_owned(),
-        None => {
-            print_step(date_cached.date, job_id, 1, total_count);
-            fetch_image_url_from_date(client, date_cached.date, api)
-                .await
-                .map_err(|error| format!("Fetching image url - {}", error))?
+    cache_writer: Option<&CacheWriter>,
+) -> Result<(DynamicImage, &'a str), AttemptError> {
+    let mut unavailable = Vec::new();
+    let mut extraction_failed = false;
+
+    for step in api.sources {
+        let result = fetch_image_from_step(
+            client,
+            date_cached,
+            job_id,
+            total_count,
+            api,
+            *step,
+            cache_writer,
+        )
+        .await;
+        match result {
+            Ok(image) => return Ok((image, step.name())),
+            // Image is found again by next source, and new URL is saved to cache file
+            Err(error) if matches!(step, SourceStep::Cache) && error.is_stale_url() => {
+                println!(
+                    "    {YELLOW}Stale{RESET} {BOLD}{}{RESET} {DIM}- Cached URL failed ({}), trying next source{RESET}",
+                    date_cached.date, error,
+                );
+                unavailable.push(format!("{BOLD}{}{RESET}: {}", step.name(), error));
+            }
+            Err(error) if error.is_permanent() => {
+                extraction_failed |= error.is_extraction_failure();
+                unavailable.push(format!("{BOLD}{}{RESET}: {}", step.name(), error));
+            }
+            Err(error) => return Err(AttemptError::Failed(error)),
+        }
+    }
+
+    Err(AttemptError::Unavailable {
+        reason: format!("Not available from any source - {}", unavailable.join("; ")),
+        extraction_failed,
+    })
+}
+
+async fn fetch_image_from_step<'a>(
+    client: &Client,
+    date_cached: &DateUrlCached,
+    job_id: usize,
+    total_count: usize,
+    api: Api<'a>,
+    step: SourceStep<'a>,
+    cache_writer: Option<&CacheWriter>,
+) -> Result<DynamicImage, FetchError> {
+    let image_url = match step {
+        SourceStep::Cache => match &date_cached.cached {
+            Some(cached) => cached.url.to_owned(),
+            None => return Err(FetchError::NotAvailable("Date is not cached".to_string())),
+        },
+        SourceStep::Source(source) => {
+            print_step(date_cached.date, job_id, 1, total_count, source.name());
+            resolve_image_url(client, date_cached.date, api, source).await?
         }
     };
 
-    if let Some(cache_file) = cache_file {
-        cache::append_cache_file(date_cached.date, &image_url, cache_file)?;
+    // Show which cache file provided URL
+    let step_name = match (step, &date_cached.cache_layer_name) {
+        (SourceStep::Cache, Some(layer_name)) => format!("{} ({})", step.name(), layer_name),
+        _ => step.name().to_string(),
+    };
+
+    print_step(date_cached.date, job_id, 2, total_count, &step_name);
+    // Cache files and webpages are not trusted to read local files
+    let allow_local_file = matches!(step, SourceStep::Source(source) if source.reads_local_files());
+    let (image_bytes, content_type) =
+        fetch_image_bytes_from_url(client, api.rate_limiter, &image_url, allow_local_file).await?;
+
+    if let SourceStep::Source(source) = step {
+        source
+            .validate_image(content_type.as_deref(), &image_bytes)
+            .map_err(|error| FetchError::Decode(format!("Invalid image - {error}")))?;
     }
 
-    print_step(date_cached.date, job_id, 2, total_count);
-    let image_bytes = fetch_image_bytes_from_url(client, &image_url)
-        .await
-        .map_err(|error| format!("Fetching image bytes - {}", error))?;
+    // Corrupt or changed image should be fetched again from next source
+    if let (SourceStep::Cache, Some(cached)) = (step, &date_cached.cached) {
+        cached
+            .verify(&image_bytes)
+            .map_err(|error| FetchError::Decode(format!("Image does not match cache - {error}")))?;
+    }
 
-    print_step(date_cached.date, job_id, 3, total_count);
+    print_step(date_cached.date, job_id, 3, total_count, &step_name);
     let image = image::load_from_memory(&image_bytes)
-        .map_err(|error| format!("Parsing image - {}", error))?;
+        .map_err(|error| FetchError::Decode(error.to_string()))?;
+
+    // Local file URLs are not useful to other users of the cache
+    let cache_writer = cache_writer.filter(|_| cache::is_remote_url(&image_url));
+    if let Some(cache_writer) = cache_writer {
+        let source_name = match step {
+            SourceStep::Cache => date_cached
+                .cached
+                .as_ref()
+                .and_then(|cached| cached.source.as_deref()),
+            SourceStep::Source(source) => Some(source.name()),
+        };
+        let entry = CacheEntry::from_image(
+            image_url,
+            &image_bytes,
+            (image.width(), image.height()),
+            source_name,
+        );
+        cache_writer.append(date_cached.date, &entry);
+    }
 
     Ok(image)
 }
 
-async fn fetch_image_url_from_date<'a>(
+/// Read image from local file, for `file://` URLs
+async fn read_image_bytes_from_file(url: &str) -> Result<(Bytes, Option<String>), FetchError> {
+    let Some(path) = Url::parse(url).ok().and_then(|url| url.to_file_path().ok()) else {
+        return Err(FetchError::NotAvailable(format!(
+            "Invalid file URL ({url})"
+        )));
+    };
+    match tokio::fs::read(&path).await {
+        Ok(bytes) => Ok((Bytes::from(bytes), None)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Err(FetchError::NotAvailable(
+            format!("Image file not found ({})", path.display()),
+        )),
+        Err(error) => Err(FetchError::Other(format!(
+            "Reading image file ({}) - {error}",
+            path.display()
+        ))),
+    }
+}
+
+/// Follow lookup steps of a source, to find image URL
+async fn resolve_image_url<'a>(
     client: &Client,
     date: NaiveDate,
     api: Api<'a>,
-) -> Result<String, String> {
-    let url = api.get_page_url(date);
+    source: &dyn Source,
+) -> Result<String, FetchError> {
+    let mut lookup = source.lookup(api.comic, date);
+    loop {
+        lookup = match lookup {
+            Lookup::Search(url) => {
+                let body =
+                    fetch_archived(client, date, api, source, &url, ArchivedKind::Search).await?;
+                let result = source.read_search_results(api.comic, date, &body);
+                write_archived(
+                    date,
+                    api,
+                    source,
+                    ArchivedKind::Search,
+                    &body,
+                    result.is_ok(),
+                );
+                result.map_err(|error| FetchError::UrlExtraction { url, error })?
+            }
+            Lookup::Page(page_url) => {
+                let body = fetch_archived(client, date, api, source, &page_url, ArchivedKind::Page)
+                    .await?;
+                let result = source.find_image_url(&page_url, &body);
+                write_archived(date, api, source, ArchivedKind::Page, &body, result.is_ok());
+                return result.map_err(|error| FetchError::UrlExtraction {
+                    url: page_url,
+                    error,
+                });
+            }
+            Lookup::Image(image_url) => return Ok(image_url),
+            Lookup::Unavailable => {
+                return Err(FetchError::NotAvailable(
+                    "Comic is not available from source".to_string(),
+                ))
+            }
+        };
+    }
+}
 
-    let response = client
-        .get(&url)
-        .send()
-        .await
-        .map_err(format_request_error)?
-        .error_for_status()
-        .map_err(format_request_error)?;
+/// Fetch webpage or search results, or read it from page archive with `--reparse`
+///
+/// Only webpages are fetched through proxy
+async fn fetch_archived(
+    client: &Client,
+    date: NaiveDate,
+    api: Api<'_>,
+    source: &dyn Source,
+    url: &str,
+    kind: ArchivedKind,
+) -> Result<String, FetchError> {
+    let archive = api.page_archive;
 
-    let response_body = response.text().await.map_err(|error| {
-        format!("Converting webpage body for image URL to text ({url}) - {error}")
-    })?;
+    if let Some(archive) = archive.filter(|archive| archive.is_reparse()) {
+        return match archive.read(api.comic, source.name(), date, kind) {
+            Ok(Some(body)) => Ok(body),
+            Ok(None) => Err(FetchError::NotAvailable(
+                "Webpage is not archived".to_string(),
+            )),
+            Err(error) => Err(FetchError::Other(format!(
+                "Reading archived webpage - {error}"
+            ))),
+        };
+    }
 
-    let Some(image_url) = api.source.find_image_url(&response_body) else {
-        return Err(format!("Cannot find image URL in webpage body ({url})"));
+    let (request, ticket) = if kind == ArchivedKind::Page && source.use_proxy() {
+        api.build_page_request(client, url)
+    } else {
+        (client.get(url), None)
     };
+    let proxy_ticket = api.proxy.zip(ticket);
+    fetch_text(api, request, url, proxy_ticket).await
+}
 
-    Ok(image_url.to_owned())
+/// Store fetched webpage or search results in page archive, if archive keeps it
+///
+/// Archive is only for re-parsing, so failing to write does not fail download
+fn write_archived(
+    date: NaiveDate,
+    api: Api<'_>,
+    source: &dyn Source,
+    kind: ArchivedKind,
+    body: &str,
+    extracted: bool,
+) {
+    let Some(archive) = api
+        .page_archive
+        .filter(|archive| archive.should_write(extracted))
+    else {
+        return;
+    };
+    if let Err(error) = archive.write(api.comic, source.name(), date, kind, body) {
+        eprintln!(
+            "{YELLOW}[warning]{RESET} {BOLD}{date}{RESET} Failed to archive webpage - {error}"
+        );
+    }
 }
 
-async fn fetch_image_bytes_from_url(client: &Client, url: &str) -> Result<Bytes, String> {
-    let response = client
-        .get(url)
-        .send()
+/// Fetch webpage or search results, as text
+///
+/// URL is only used for error messages. Result is reported to proxy pool, if request was sent
+/// through a proxy
+async fn fetch_text(
+    api: Api<'_>,
+    request: RequestBuilder,
+    url: &str,
+    proxy_ticket: Option<(&ProxyPool, ProxyTicket)>,
+) -> Result<String, FetchError> {
+    let map_failure = |failure: RequestFailure| FetchError::PageFetch {
+        url: url.to_string(),
+        failure,
+    };
+    let map_request_error = |error: reqwest::Error| map_failure(RequestFailure::from_error(error));
+
+    let (client, request) = request.build_split();
+    let request = request.map_err(map_request_error)?;
+    // Target of proxied request is limited as well as proxy
+    let target_url = Url::parse(url).ok();
+    let target_host = target_url.as_ref().and_then(Url::host_str);
+    let hosts: Vec<_> = [request.url().host_str(), target_host]
+        .into_iter()
+        .flatten()
+        .collect();
+    api.rate_limiter.wait_for_hosts(&hosts).await;
+
+    // Latency does not include waiting for rate limit
+    let start_time = Instant::now();
+    let response = client.execute(request).await;
+    if let Some((pool, ticket)) = proxy_ticket {
+        let status = response.as_ref().ok().map(|response| response.status());
+        pool.report(ticket, status, start_time.elapsed());
+    }
+    let response = response.map_err(map_request_error)?;
+
+    let response = RequestFailure::check_status(response).map_err(map_failure)?;
+
+    response.text().await.map_err(map_request_error)
+}
+
+/// Returns image bytes, and `Content-Type` header if present
+async fn fetch_image_bytes_from_url(
+    client: &Client,
+    rate_limiter: &RateLimiter,
+    url: &str,
+    allow_local_file: bool,
+) -> Result<(Bytes, Option<String>), FetchError> {
+    if url.starts_with("file://") {
+        if !allow_local_file {
+            return Err(FetchError::NotAvailable(format!(
+                "Local file URL is only read from local folder sources ({})",
+                url
+            )));
+        }
+        return read_image_bytes_from_file(url).await;
+    }
+
+    let map_failure = |failure: RequestFailure| FetchError::ImageFetch {
+        url: url.to_string(),
+        failure,
+    };
+    let map_request_error = |error: reqwest::Error| map_failure(RequestFailure::from_error(error));
+
+    let response = rate_limiter
+        .send(client.get(url))
         .await
-        .map_err(format_request_error)?
-        .error_for_status()
-        .map_err(format_request_error)?;
+        .map_err(map_request_error)?;
+
+    let mut response = RequestFailure::check_status(response).map_err(map_failure)?;
 
-    let bytes = response.bytes().await.map_err(format_request_error)?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    // Read in chunks, so bandwidth can be limited
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = response.chunk().await.map_err(map_request_error)? {
+        rate_limiter.wait_for_bytes(chunk.len()).await;
+        bytes.extend_from_slice(&chunk);
+    }
+    let bytes = bytes.freeze();
 
-    Ok(bytes)
+    Ok((bytes, content_type))
 }
@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::api::ExtractError;
+use crate::cache;
+use crate::format_request_error;
+
+/// Reason a single source step could not provide an image, by stage of download
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    /// Source does not have image for this date
+    #[error("{0}")]
+    NotAvailable(String),
+    /// Webpage or search results could not be fetched
+    #[error("Fetching webpage ({url}) - {failure}")]
+    PageFetch {
+        url: String,
+        failure: RequestFailure,
+    },
+    /// Image URL could not be found in webpage or search results
+    #[error("{error} ({url})")]
+    UrlExtraction { url: String, error: ExtractError },
+    /// Image could not be fetched
+    #[error("Fetching image ({url}) - {failure}")]
+    ImageFetch {
+        url: String,
+        failure: RequestFailure,
+    },
+    /// Image is invalid, or could not be parsed
+    #[error("Decoding image - {0}")]
+    Decode(String),
+    /// Failure not caused by source, eg. reading a local file or writing cache file
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Request which failed, or received an error status
+#[derive(Debug)]
+pub struct RequestFailure {
+    message: String,
+    status: Option<StatusCode>,
+    is_timeout: bool,
+    /// Delay requested by server with `Retry-After`
+    retry_after: Option<Duration>,
+}
+
+impl FetchError {
+    /// Whether retrying would not help, so next source should be tried instead
+    ///
+    /// If all sources fail permanently, date is skipped
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::NotAvailable(_) | Self::UrlExtraction { .. } | Self::Decode(_) => true,
+            Self::PageFetch { failure, .. } | Self::ImageFetch { failure, .. } => {
+                failure.is_permanent()
+            }
+            Self::Other(_) => false,
+        }
+    }
+
+    /// Image URL could not be found, or image could not be decoded
+    ///
+    /// Retrying would not help, but if every date fails this way, the source may have changed
+    pub fn is_extraction_failure(&self) -> bool {
+        matches!(self, Self::UrlExtraction { .. } | Self::Decode(_))
+    }
+
+    /// Image URL is no longer valid, and will not become valid by retrying
+    pub fn is_stale_url(&self) -> bool {
+        match self {
+            Self::ImageFetch { failure, .. } => failure.status.is_some_and(cache::is_stale_status),
+            _ => false,
+        }
+    }
+
+    /// Failed request, if request timed out or was rate limited
+    pub fn get_overload(&self) -> Option<&RequestFailure> {
+        match self {
+            Self::PageFetch { failure, .. } | Self::ImageFetch { failure, .. }
+                if failure.is_overload() =>
+            {
+                Some(failure)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RequestFailure {
+    pub fn from_error(error: reqwest::Error) -> Self {
+        Self {
+            status: error.status(),
+            is_timeout: error.is_timeout(),
+            retry_after: None,
+            message: format_request_error(error),
+        }
+    }
+
+    /// Check response status, including `Retry-After` delay if rate limited
+    pub fn check_status(response: Response) -> Result<Response, Self> {
+        let retry_after = get_retry_after(&response);
+        response.error_for_status().map_err(|error| Self {
+            retry_after,
+            ..Self::from_error(error)
+        })
+    }
+
+    /// Webpage or image does not exist
+    fn is_permanent(&self) -> bool {
+        matches!(self.status, Some(StatusCode::NOT_FOUND | StatusCode::GONE))
+    }
+
+    fn is_overload(&self) -> bool {
+        self.is_timeout
+            || matches!(
+                self.status,
+                Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+            )
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+}
+
+impl Display for RequestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Parse `Retry-After` header, as seconds or an HTTP date
+fn get_retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
@@ -26,4 +26,31 @@ pub enum Error {
 
     #[error("..")]
     BadStartDate,
+
+    #[error("..")]
+    UnknownSource,
+
+    #[error("..")]
+    UnknownComic,
+
+    #[error("..")]
+    SourcesFile,
+
+    #[error("..")]
+    LocalSource,
+
+    #[error("..")]
+    BadProxy,
+
+    #[error("..")]
+    BadTls,
+
+    #[error("..")]
+    Offline,
+
+    #[error("..")]
+    CacheCommand,
+
+    #[error("..")]
+    ExtractFail,
 }
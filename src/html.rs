@@ -0,0 +1,302 @@
+//! Minimal helpers for finding values in HTML pages, for use in [crate::api::Source] implementations
+//!
+//! These do not fully parse HTML, but only look for specific tags, so should be resilient to
+//! unrelated layout changes
+
+/// Find `content` attribute of `<meta>` tags with matching `property` or `name` attribute
+///
+/// Eg. `og:image`
+pub fn find_meta_contents(body: &str, property: &str) -> Vec<String> {
+    find_tags(body, "meta")
+        .filter(|tag| {
+            ["property", "name"].iter().any(|key| {
+                get_attribute(tag, key).is_some_and(|value| value.eq_ignore_ascii_case(property))
+            })
+        })
+        .filter_map(|tag| get_attribute(tag, "content"))
+        .collect()
+}
+
+/// Find all values of `image` keys in JSON-LD (`<script type="application/ld+json">`) blocks
+///
+/// Values may be strings, arrays, or `ImageObject`s with a `url` or `contentUrl` key
+pub fn find_json_ld_images(body: &str) -> Vec<String> {
+    let mut images = Vec::new();
+    for (tag, content) in find_elements(body, "script") {
+        let is_json_ld = get_attribute(tag, "type")
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("application/ld+json"));
+        if !is_json_ld {
+            continue;
+        }
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(content.trim()) else {
+            continue;
+        };
+        collect_json_images(&json, &mut images);
+    }
+    images
+}
+
+/// Find image sources (`<img src>` and first URL of `<source srcset>`) inside `<picture>` elements
+pub fn find_picture_sources(body: &str) -> Vec<String> {
+    let mut sources = Vec::new();
+    for (_, content) in find_elements(body, "picture") {
+        for tag in find_tags(content, "source") {
+            if let Some(srcset) = get_attribute(tag, "srcset") {
+                if let Some(url) = srcset.split_whitespace().next() {
+                    sources.push(url.trim_end_matches(',').to_string());
+                }
+            }
+        }
+        for tag in find_tags(content, "img") {
+            if let Some(src) = get_attribute(tag, "src") {
+                sources.push(src);
+            }
+        }
+    }
+    sources
+}
+
+fn collect_json_images(json: &serde_json::Value, images: &mut Vec<String>) {
+    use serde_json::Value;
+    match json {
+        Value::Array(items) => {
+            for item in items {
+                collect_json_images(item, images);
+            }
+        }
+        Value::Object(map) => {
+            if let Some(image) = map.get("image") {
+                collect_json_image_value(image, images);
+            }
+            // Nested objects, eg. `@graph`
+            for (key, value) in map {
+                if key != "image" {
+                    collect_json_images(value, images);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn collect_json_image_value(value: &serde_json::Value, images: &mut Vec<String>) {
+    use serde_json::Value;
+    match value {
+        Value::String(url) => images.push(url.clone()),
+        Value::Array(items) => {
+            for item in items {
+                collect_json_image_value(item, images);
+            }
+        }
+        Value::Object(map) => {
+            let url = map.get("url").or_else(|| map.get("contentUrl"));
+            if let Some(Value::String(url)) = url {
+                images.push(url.clone());
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Iterate over the attribute sections of all opening tags with a given name
+///
+/// Eg. for `<meta property="og:image" content="...">`, yields ` property="og:image" content="..."`
+fn find_tags<'a>(body: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut rest = body;
+    std::iter::from_fn(move || {
+        let (tag, after) = next_tag(rest, name)?;
+        rest = after;
+        Some(tag)
+    })
+}
+
+/// Iterate over attributes and inner content of all elements with a given name
+fn find_elements<'a>(
+    body: &'a str,
+    name: &'a str,
+) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+    let closing = format!("</{}", name);
+    let mut rest = body;
+    std::iter::from_fn(move || {
+        let (tag, content) = next_tag(rest, name)?;
+        let end = find_ignore_case(content, &closing).unwrap_or(content.len());
+        rest = &content[end..];
+        Some((tag, &content[..end]))
+    })
+}
+
+/// Find next opening tag with a given name
+///
+/// Returns attribute section of tag, and remaining body after tag
+fn next_tag<'a>(body: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let mut rest = body;
+    loop {
+        let start = find_ignore_case(rest, &format!("<{}", name))?;
+        let after_name = &rest[start + name.len() + 1..];
+        // Tag name must end here, eg. `<meta` but not `<metadata`
+        let is_whole_name = after_name
+            .chars()
+            .next()
+            .is_some_and(|ch| ch.is_whitespace() || ch == '>' || ch == '/');
+        let end = after_name.find('>')?;
+        rest = &after_name[end + 1..];
+        if is_whole_name {
+            return Some((after_name[..end].trim_end_matches('/'), rest));
+        }
+    }
+}
+
+/// Get value of attribute in a tag, with HTML entities decoded
+fn get_attribute(tag: &str, key: &str) -> Option<String> {
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let name_end = rest
+            .find(|ch: char| ch.is_whitespace() || ch == '=')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            None => "",
+            Some(after_equals) => {
+                let after_equals = after_equals.trim_start();
+                let (value, remaining) = match after_equals.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let inner = &after_equals[1..];
+                        let end = inner.find(quote).unwrap_or(inner.len());
+                        (&inner[..end], inner.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = after_equals
+                            .find(char::is_whitespace)
+                            .unwrap_or(after_equals.len());
+                        after_equals.split_at(end)
+                    }
+                };
+                rest = remaining;
+                value
+            }
+        };
+
+        if name.eq_ignore_ascii_case(key) {
+            return Some(decode_entities(value));
+        }
+    }
+}
+
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&#x2F;", "/")
+        .replace("&#47;", "/")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Simple CSS selector, supporting a tag name with attribute, class, and ID filters
+///
+/// Eg. `meta[property="og:image"]`, `img.comic`, `img#strip[src]`.
+/// Combinators (eg. `picture > img`) are not supported
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selector {
+    tag: String,
+    filters: Vec<AttributeFilter>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum AttributeFilter {
+    /// `[key]`
+    Exists(String),
+    /// `[key=value]`, and `#value` for `id`
+    Equals(String, String),
+    /// `.value` for `class`
+    ContainsWord(String, String),
+}
+
+impl std::str::FromStr for Selector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let selector = selector.trim();
+        let tag_end = selector.find(['[', '.', '#']).unwrap_or(selector.len());
+        let tag = &selector[..tag_end];
+        if tag.is_empty()
+            || !tag
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+        {
+            return Err(format!(
+                "Selector `{selector}` must start with a tag name, and cannot contain combinators"
+            ));
+        }
+
+        let mut filters = Vec::new();
+        let mut rest = &selector[tag_end..];
+        while let Some(first) = rest.chars().next() {
+            rest = &rest[first.len_utf8()..];
+            match first {
+                '[' => {
+                    let end = rest
+                        .find(']')
+                        .ok_or_else(|| format!("Unclosed `[` in selector `{selector}`"))?;
+                    let filter = &rest[..end];
+                    rest = &rest[end + 1..];
+                    filters.push(match filter.split_once('=') {
+                        None => AttributeFilter::Exists(filter.trim().to_string()),
+                        Some((key, value)) => AttributeFilter::Equals(
+                            key.trim().to_string(),
+                            value.trim().trim_matches(['"', '\'']).to_string(),
+                        ),
+                    });
+                }
+                '.' | '#' => {
+                    let end = rest.find(['[', '.', '#']).unwrap_or(rest.len());
+                    let value = rest[..end].to_string();
+                    rest = &rest[end..];
+                    filters.push(if first == '.' {
+                        AttributeFilter::ContainsWord("class".to_string(), value)
+                    } else {
+                        AttributeFilter::Equals("id".to_string(), value)
+                    });
+                }
+                _ => return Err(format!("Unsupported selector `{selector}`")),
+            }
+        }
+
+        Ok(Self {
+            tag: tag.to_string(),
+            filters,
+        })
+    }
+}
+
+/// Find values of an attribute, in all tags matching selector
+pub fn select_attributes(body: &str, selector: &Selector, attribute: &str) -> Vec<String> {
+    find_tags(body, &selector.tag)
+        .filter(|tag| {
+            selector.filters.iter().all(|filter| match filter {
+                AttributeFilter::Exists(key) => get_attribute(tag, key).is_some(),
+                AttributeFilter::Equals(key, expected) => {
+                    get_attribute(tag, key).is_some_and(|value| &value == expected)
+                }
+                AttributeFilter::ContainsWord(key, expected) => get_attribute(tag, key)
+                    .is_some_and(|value| value.split_whitespace().any(|word| word == expected)),
+            })
+        })
+        .filter_map(|tag| get_attribute(tag, attribute))
+        .collect()
+}
@@ -2,14 +2,16 @@ use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
-pub fn get_folder_path(folder: Option<&str>) -> Result<PathBuf, String> {
+use crate::comics::Comic;
+
+pub fn get_folder_path(folder: Option<&str>, comic: &Comic) -> Result<PathBuf, String> {
     let folder = folder.map(|folder| Path::new(&folder).to_path_buf());
 
     if let Some(folder) = folder {
         return Ok(folder);
     }
     if let Some(folder) = get_generic_parent_folder() {
-        return Ok(folder.join(Path::new("garfield")));
+        return Ok(folder.join(Path::new(comic.folder_name)));
     }
 
     Err(
@@ -18,6 +20,25 @@ pub fn get_folder_path(folder: Option<&str>) -> Result<PathBuf, String> {
     )
 }
 
+/// Default location of user-defined sources file (~/.config/everygarf/sources.toml)
+pub fn get_default_sources_file() -> Option<PathBuf> {
+    Some(
+        dirs_next::config_dir()?
+            .join("everygarf")
+            .join("sources.toml"),
+    )
+}
+
+/// Default location of page archive (~/.cache/everygarf/pages)
+pub fn get_default_page_archive_dir() -> Option<PathBuf> {
+    Some(dirs_next::cache_dir()?.join("everygarf").join("pages"))
+}
+
+/// Default location of local copies of remote cache files (~/.cache/everygarf/cache)
+pub fn get_default_cache_mirror_dir() -> Option<PathBuf> {
+    Some(dirs_next::cache_dir()?.join("everygarf").join("cache"))
+}
+
 fn get_generic_parent_folder() -> Option<PathBuf> {
     use dirs_next::*;
     picture_dir().or_else(document_dir).or_else(home_dir)
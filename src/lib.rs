@@ -1,11 +1,18 @@
 #![allow(clippy::uninlined_format_args)]
 
 pub mod api;
+pub mod archive;
+pub mod cache;
 pub mod colors;
+pub mod comics;
 pub mod dates;
 pub mod errors;
+pub mod html;
+pub mod proxy;
+pub mod rate_limit;
+pub mod tls;
 
-mod cache;
+mod concurrency;
 mod download;
 mod io;
 
@@ -13,16 +20,30 @@ mod io;
 mod tests;
 
 pub use crate::errors::Error;
-pub use crate::io::{create_target_dir, get_folder_path};
+pub use crate::io::{
+    create_target_dir, get_default_cache_mirror_dir, get_default_page_archive_dir,
+    get_default_sources_file, get_folder_path,
+};
 
 use chrono::NaiveDate;
-use futures::{stream, StreamExt};
+use futures::{stream::FuturesUnordered, StreamExt};
 use reqwest::{Client, StatusCode};
-use std::{fs, path::Path, process, time::Duration};
-
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use crate::api::{Api, Lookup, SourceRegistry, SourceStep};
+use crate::cache::{CacheStatus, CacheWriter, DateUrlCached};
 use crate::colors::*;
+use crate::concurrency::ConcurrencyController;
 use crate::dates::date_from_filename;
-use crate::{api::Api, cache::DateUrlCached};
+use crate::download::DownloadError;
+use crate::proxy::ProxyStats;
+use crate::tls::TlsConfig;
 
 pub const PROXY_DEFAULT: &str = "https://proxy.darcy-700.workers.dev/cors-proxy";
 pub const CACHE_DEFAULT: &str =
@@ -38,9 +59,6 @@ const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/
 
 static mut PROGRESS_COUNT: u32 = 0;
 
-const IMAGE_URL_PREFIX: &str = "https://featureassets.gocomics.com/assets/";
-const IMAGE_URL_LENGTH: usize = 74;
-
 pub fn get_existing_dates(folder: &Path) -> Result<Vec<NaiveDate>, String> {
     Ok(crate::io::get_child_filenames(folder)
         .map_err(|err| format!("read directory - {:#?}", err))?
@@ -55,11 +73,17 @@ pub struct Downloader<'a, 'b, 'c> {
     pub folder: &'b Path,
     pub dates: &'c [NaiveDate],
     pub job_count: usize,
-    pub cache_url: Option<String>,
+    /// Cache files (local or remote), in order of precedence
+    pub cache_urls: Vec<String>,
+    /// Folder to keep local copy of remote cache files in
+    pub cache_mirror_dir: Option<PathBuf>,
     pub always_ping: bool,
     pub timeout_main: Duration,
     pub timeout_initial: Duration,
     pub notify_on_fail: bool,
+    /// Forward proxy for all requests, see [proxy::build_forward_proxy]
+    pub forward_proxy: Option<reqwest::Proxy>,
+    pub tls: TlsConfig,
 }
 
 /// Options which are passed to [download::download_image], for downloading a single image
@@ -67,120 +91,496 @@ pub struct Downloader<'a, 'b, 'c> {
 pub struct SingleDownloadOptions<'a> {
     pub attempt_count: u32,
     pub api: Api<'a>,
-    pub cache_file: Option<&'a str>,
+    pub cache_writer: Option<&'a CacheWriter>,
     pub image_format: &'a str,
     pub save_as_tree: bool,
+    pub retry: RetrySchedule,
+}
+
+/// Delay between failed attempts: exponential backoff with jitter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetrySchedule {
+    /// Delay after first failed attempt
+    pub base: Duration,
+    /// Maximum delay, before jitter
+    pub max: Duration,
+}
+
+impl RetrySchedule {
+    /// Get delay after a failed attempt (starting at 1)
+    ///
+    /// Random delay is between half and all of the backoff, so that jobs do not retry at the same
+    /// time. `Retry-After` delay from server is respected, up to maximum delay
+    pub fn get_delay(&self, attempt_no: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = self
+            .base
+            .saturating_mul(1 << attempt_no.clamp(1, 16).saturating_sub(1))
+            .min(self.max);
+        let jitter = backoff.mul_f64(fastrand::f64() / 2.0);
+        let delay = backoff / 2 + jitter;
+        match retry_after {
+            Some(retry_after) => delay.max(retry_after.min(self.max)),
+            None => delay,
+        }
+    }
+}
+
+impl Default for RetrySchedule {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Summary of a completed download, for displaying to user
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Amount of images downloaded from each source, by source name
+    pub source_counts: BTreeMap<String, usize>,
+    /// Concurrent downloads chosen by end of download
+    pub concurrency: usize,
+    /// Highest concurrent downloads during download
+    pub peak_concurrency: usize,
+    /// Dates which are not available from any source, with reason
+    pub skipped: Vec<(NaiveDate, String)>,
+    /// Health of each proxy in pool, if used
+    pub proxy_stats: Vec<(String, ProxyStats)>,
+    /// Amount of images downloaded with URLs from each cache file, in order of precedence
+    pub cache_layer_counts: Vec<(String, usize)>,
+}
+
+/// Estimate of a download, made without network access. See [Downloader::plan_offline]
+#[derive(Debug, Default)]
+pub struct OfflinePlan {
+    /// Each step of source chain, in order
+    pub sources: Vec<PlannedSource>,
+    /// Dates which no source could provide
+    pub unavailable: Vec<NaiveDate>,
+    /// Amount of images which would be served from each cache file, in order of precedence
+    pub cache_layer_counts: Vec<(String, usize)>,
+}
+
+/// Images which would be downloaded from a single source
+#[derive(Debug, Default)]
+pub struct PlannedSource {
+    pub name: String,
+    pub image_count: usize,
+    /// Estimated webpage and image requests
+    pub request_count: usize,
+    /// Whether webpages must be fetched to find image URLs
+    pub requires_scraping: bool,
+}
+
+impl OfflinePlan {
+    pub fn request_count(&self) -> usize {
+        self.sources.iter().map(|source| source.request_count).sum()
+    }
 }
 
 impl<'a> Downloader<'a, '_, '_> {
-    pub async fn download_all_images(self) {
+    pub async fn download_all_images(self) -> DownloadReport {
         let SingleDownloadOptions {
-            api, cache_file, ..
+            api, cache_writer, ..
         } = self.single_download_options;
 
-        let client_initial = Client::builder()
-            .user_agent(USER_AGENT)
-            .timeout(self.timeout_initial)
-            .build()
-            .expect("Failed to build request client (initial). This error should never occur.");
+        // Can only fail with invalid TLS configuration
+        let map_client_error = |error: reqwest::Error| {
+            fatal_error(
+                Error::BadTls,
+                format!("Failed to build request client - {}", error),
+                self.notify_on_fail,
+            )
+        };
+
+        let client_initial = self
+            .build_client(self.timeout_initial)
+            .unwrap_or_else(map_client_error);
 
-        let client_main = Client::builder()
-            .user_agent(USER_AGENT)
-            .timeout(self.timeout_main)
-            .build()
-            .expect("Failed to build request client (main). This error should never occur.");
+        let client_main = self.build_main_client().unwrap_or_else(map_client_error);
 
-        if let Some(proxy) = api.proxy {
+        if let Some(proxy_pool) = api.proxy {
             if !self.always_ping && self.dates.len() < MIN_COUNT_FOR_PING {
                 println!("    {DIM}(Skipping proxy ping){RESET}");
             } else {
-                println!("    {DIM}Pinging proxy server...{RESET}");
-                if let Err(error) = api::check_proxy_service(&client_initial, proxy).await {
-                    let message = format!(
-                "{RED}{BOLD}Proxy service unavailable{RESET} - {}.\n{DIM}Trying to ping {UNDERLINE}{}{RESET}\nPlease try later, or create an issue at {ISSUE_URL}",
-                proxy,
-                format_request_error(error),
+                println!("    {DIM}Pinging proxy servers...{RESET}");
+                let results = proxy_pool.check_all(&client_initial).await;
+                let mut failures = Vec::new();
+                for (proxy, result) in results {
+                    if let Err(error) = result {
+                        println!(
+                            "    {YELLOW}Proxy unavailable:{RESET} {DIM}{}{RESET}",
+                            proxy
+                        );
+                        failures.push(format!(
+                            "{UNDERLINE}{}{RESET}{DIM} - {}",
+                            proxy,
+                            format_request_error(error),
+                        ));
+                    }
+                }
+                if proxy_pool.alive_count() == 0 {
+                    if proxy_pool.allows_direct() {
+                        println!("    {YELLOW}No proxy services available. Sending requests directly{RESET}");
+                    } else {
+                        let message = format!(
+                "{RED}{BOLD}Proxy service unavailable{RESET}.\n{DIM}Trying to ping {}{RESET}\nPlease try later, or create an issue at {ISSUE_URL}",
+                failures.join(", "),
             );
-                    fatal_error(Error::ProxyPing, message, self.notify_on_fail);
+                        fatal_error(Error::ProxyPing, message, self.notify_on_fail);
+                    }
                 }
             }
         }
 
-        let dates_cached: Vec<_> = match self.cache_url {
-            Some(cache_url) => {
-                if cache::is_remote_url(&cache_url) {
-                    println!("    {DIM}Downloading cached URLs...{RESET}");
-                } else {
-                    println!("    {DIM}Reading cached URLs...{RESET}");
+        // Cache is not needed if it is not in the source chain
+        let cache_urls: &[String] = if api.uses_cache() {
+            &self.cache_urls
+        } else {
+            &[]
+        };
+        let mut layers = Vec::new();
+        for cache_url in cache_urls {
+            // Only name cache file if there are multiple
+            let name = if cache_urls.len() > 1 {
+                format!(" from {UNDERLINE}{}{RESET}{DIM}", cache_url)
+            } else {
+                String::new()
+            };
+            if cache::is_remote_url(cache_url) {
+                println!("    {DIM}Downloading cached URLs{}...{RESET}", name);
+            } else {
+                println!("    {DIM}Reading cached URLs{}...{RESET}", name);
+            }
+            match cache::fetch_cached_urls(
+                &client_initial,
+                api.rate_limiter,
+                cache_url,
+                self.cache_mirror_dir.as_deref(),
+            )
+            .await
+            {
+                Ok((dates, status)) => {
+                    print_cache_status(&status);
+                    layers.push(dates);
                 }
-                let cached_dates = match cache::fetch_cached_urls(&client_initial, &cache_url).await
-                {
-                    Ok(dates) => dates,
-                    Err(error) => {
-                        let message = format!(
+                Err(error) => {
+                    let message = format!(
                         "{}\n{RESET}{DIM}Please try running with `--no-cache` argument, or create an issue at {ISSUE_URL}{RESET}",
                         error,
                     );
-                        fatal_error(Error::CacheDownload, message, self.notify_on_fail)
-                    }
-                };
-                self.dates
-                    .iter()
-                    .map(|date| DateUrlCached {
-                        date: *date,
-                        url: cached_dates.get(date).cloned(),
-                    })
-                    .collect()
+                    fatal_error(Error::CacheDownload, message, self.notify_on_fail)
+                }
             }
-            None => self
-                .dates
-                .iter()
-                .map(|date| DateUrlCached {
+        }
+        let mut cached_dates = cache::merge_layers(layers);
+        let dates_cached: Vec<_> = self
+            .dates
+            .iter()
+            .map(|date| {
+                let (cache_layer, cached) = match cached_dates.remove(date) {
+                    Some((cache_layer, entry)) => (cache_layer, Some(entry)),
+                    None => (0, None),
+                };
+                // Only name cache file if there are multiple
+                let cache_layer_name = cached
+                    .as_ref()
+                    .filter(|_| cache_urls.len() > 1)
+                    .map(|_| cache_urls[cache_layer].clone());
+                DateUrlCached {
                     date: *date,
-                    url: None,
-                })
-                .collect(),
-        };
+                    cached,
+                    cache_layer,
+                    cache_layer_name,
+                }
+            })
+            .collect();
 
         unsafe { PROGRESS_COUNT = 0 }
 
-        let bodies = stream::iter(dates_cached.iter().enumerate())
-            .map(|(i, date_cached)| {
+        let concurrency = ConcurrencyController::new(self.job_count);
+        let mut report = DownloadReport {
+            cache_layer_counts: cache_urls.iter().map(|url| (url.clone(), 0)).collect(),
+            ..Default::default()
+        };
+        let mut extraction_failure_count = 0;
+
+        // Amount of downloads in flight is chosen by controller, up to job count
+        let mut pending = dates_cached.iter().enumerate();
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < concurrency.limit() {
+                let Some((i, date_cached)) = pending.next() else {
+                    break;
+                };
                 let job_id = i % self.job_count;
                 let client = &client_main;
                 let progress = dates_cached.len();
-                async move {
-                    download::download_image(
+                let concurrency = &concurrency;
+                in_flight.push(async move {
+                    let result = download::download_image(
                         client,
                         date_cached.clone(),
                         self.folder,
                         job_id,
                         progress,
                         self.single_download_options,
+                        concurrency,
                     )
-                    .await
-                }
-            })
-            .buffered(self.job_count);
+                    .await;
+                    (date_cached, result)
+                });
+            }
 
-        bodies
-            .for_each(|result| async {
-                if let Err(error) = result {
-                    fatal_error(Error::DownloadFail, error, self.notify_on_fail);
+            let Some((date_cached, result)) = in_flight.next().await else {
+                break;
+            };
+            let date = date_cached.date;
+            match result {
+                Ok(source_name) => {
+                    if source_name == SourceRegistry::CACHE_STEP {
+                        report.cache_layer_counts[date_cached.cache_layer].1 += 1;
+                    }
+                    *report
+                        .source_counts
+                        .entry(source_name.to_string())
+                        .or_default() += 1;
                 }
-            })
-            .await;
+                Err(DownloadError::Skipped {
+                    reason,
+                    extraction_failed,
+                }) => {
+                    println!(
+                        "    {YELLOW}Skipped{RESET} {BOLD}{}{RESET} {DIM}- {}{RESET}",
+                        date, reason
+                    );
+                    extraction_failure_count += usize::from(extraction_failed);
+                    report.skipped.push((date, reason));
+                }
+                Err(DownloadError::Failed(error)) => {
+                    fatal_error(Error::DownloadFail, error, self.notify_on_fail)
+                }
+            }
+        }
+
+        report.concurrency = concurrency.limit();
+        report.peak_concurrency = concurrency.peak();
 
-        if let Some(cache_file) = cache_file {
-            if let Err(error) = cache::clean_cache_file(cache_file) {
+        if let Some(cache_writer) = cache_writer {
+            if let Err(error) = cache_writer.finish() {
                 fatal_error(
                     Error::CleanCache,
-                    format!("Failed to clean cache file - {}", error),
+                    format!(
+                        "Failed to save cache file `{}` - {}",
+                        cache_writer.path().display(),
+                        error,
+                    ),
                     self.notify_on_fail,
                 );
             }
         }
+
+        // Source has likely changed, so images will not become available by waiting
+        if report.source_counts.is_empty()
+            && !report.skipped.is_empty()
+            && extraction_failure_count == report.skipped.len()
+        {
+            let (date, reason) = &report.skipped[0];
+            fatal_error(
+                Error::ExtractFail,
+                format!(
+                    "Failed to find any images, as image URLs could not be found or images could not be decoded for all {} dates. A source may have changed\n{DIM}{}: {}{RESET}",
+                    report.skipped.len(),
+                    date,
+                    reason,
+                ),
+                self.notify_on_fail,
+            );
+        }
+
+        if let Some(proxy_pool) = api.proxy {
+            report.proxy_stats = proxy_pool
+                .stats()
+                .into_iter()
+                .map(|(proxy, stats)| (proxy.to_string(), stats))
+                .collect();
+        }
+
+        report
+    }
+
+    /// Find which source would provide each date, without opening any network connections
+    ///
+    /// Remote cache files are only used if they have been downloaded before, and are otherwise
+    /// skipped with a warning. Fails if no cache file can be used
+    pub async fn plan_offline(&self) -> Result<OfflinePlan, String> {
+        let api = self.single_download_options.api;
+
+        let cache_urls: &[String] = if api.uses_cache() {
+            &self.cache_urls
+        } else {
+            &[]
+        };
+        let mut layers = Vec::new();
+        let mut missing_urls = Vec::new();
+        for cache_url in cache_urls {
+            // Remote cache can only be used if it has been mirrored
+            let dates = if cache::is_remote_url(cache_url) {
+                let mirrored = self
+                    .cache_mirror_dir
+                    .as_deref()
+                    .and_then(|mirror_dir| cache::read_mirrored_urls(cache_url, mirror_dir));
+                let Some(mirrored) = mirrored else {
+                    println!(
+                        "    {YELLOW}Skipping remote cache file, which has not been downloaded before{RESET} {DIM}- {}{RESET}",
+                        cache_url,
+                    );
+                    missing_urls.push(cache_url.as_str());
+                    // Keep index of each layer
+                    layers.push(cache::DateMap::new());
+                    continue;
+                };
+                let (dates, age) = mirrored?;
+                print_cache_status(&CacheStatus::Fallback {
+                    age,
+                    error: "Offline mode".to_string(),
+                });
+                dates
+            } else {
+                let client = self
+                    .build_client(self.timeout_initial)
+                    .map_err(|error| format!("Failed to build request client - {}", error))?;
+                let (dates, _) =
+                    cache::fetch_cached_urls(&client, api.rate_limiter, cache_url, None).await?;
+                dates
+            };
+            layers.push(dates);
+        }
+        if !missing_urls.is_empty() && missing_urls.len() == cache_urls.len() {
+            return Err(format!(
+                "Offline mode requires a local cache file, but cache is remote ({}), and has not been downloaded before\n{DIM}Use `--cache <PATH>` for a local cache file, or `--no-cache`{RESET}",
+                missing_urls.join(", "),
+            ));
+        }
+        let cached_dates = cache::merge_layers(layers);
+
+        let mut plan = OfflinePlan {
+            sources: api
+                .sources
+                .iter()
+                .map(|step| PlannedSource {
+                    name: step.name().to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            unavailable: Vec::new(),
+            cache_layer_counts: cache_urls.iter().map(|url| (url.clone(), 0)).collect(),
+        };
+
+        // Local files do not need a request
+        let count_image_requests = |url: &str| usize::from(!url.starts_with("file://"));
+
+        'dates: for date in self.dates {
+            for (step, planned) in api.sources.iter().zip(&mut plan.sources) {
+                let request_count = match step {
+                    SourceStep::Cache => match cached_dates.get(date) {
+                        Some((cache_layer, cached)) => {
+                            plan.cache_layer_counts[*cache_layer].1 += 1;
+                            count_image_requests(&cached.url)
+                        }
+                        None => continue,
+                    },
+                    SourceStep::Source(source) => match source.lookup(api.comic, *date) {
+                        Lookup::Unavailable => continue,
+                        Lookup::Image(url) => count_image_requests(&url),
+                        Lookup::Page(_) => {
+                            planned.requires_scraping = true;
+                            2
+                        }
+                        Lookup::Search(_) => {
+                            planned.requires_scraping = true;
+                            3
+                        }
+                    },
+                };
+                planned.image_count += 1;
+                planned.request_count += request_count;
+                continue 'dates;
+            }
+            plan.unavailable.push(*date);
+        }
+
+        Ok(plan)
+    }
+
+    fn build_client(&self, timeout: Duration) -> reqwest::Result<Client> {
+        build_client(timeout, &self.tls, self.forward_proxy.as_ref())
+    }
+
+    /// Client for webpages and images
+    ///
+    /// With a bandwidth limit, timeout applies to connecting and to each read, instead of the
+    /// whole request, as large images may take longer to download than the timeout
+    fn build_main_client(&self) -> reqwest::Result<Client> {
+        let rate_limiter = self.single_download_options.api.rate_limiter;
+        if !rate_limiter.limits_bandwidth() {
+            return self.build_client(self.timeout_main);
+        }
+        let builder = Client::builder()
+            .connect_timeout(self.timeout_main)
+            .read_timeout(self.timeout_main);
+        configure_client(builder, &self.tls, self.forward_proxy.as_ref())
+    }
+}
+
+/// Build request client with TLS options and forward proxy
+pub fn build_client(
+    timeout: Duration,
+    tls: &TlsConfig,
+    forward_proxy: Option<&reqwest::Proxy>,
+) -> reqwest::Result<Client> {
+    configure_client(Client::builder().timeout(timeout), tls, forward_proxy)
+}
+
+fn configure_client(
+    builder: reqwest::ClientBuilder,
+    tls: &TlsConfig,
+    forward_proxy: Option<&reqwest::Proxy>,
+) -> reqwest::Result<Client> {
+    let mut builder = tls.apply(builder.user_agent(USER_AGENT));
+    if let Some(forward_proxy) = forward_proxy {
+        builder = builder.proxy(forward_proxy.clone());
+    }
+    builder.build()
+}
+
+fn print_cache_status(status: &CacheStatus) {
+    match status {
+        CacheStatus::Local | CacheStatus::Downloaded => (),
+        CacheStatus::NotModified { age } => {
+            println!(
+                "    {DIM}Cached URLs are up to date (downloaded {} ago){RESET}",
+                format_age(*age),
+            );
+        }
+        CacheStatus::Fallback { age, error } => {
+            println!(
+                "    {YELLOW}Using local copy of cached URLs from {} ago{RESET} {DIM}- {}{RESET}",
+                format_age(*age),
+                error,
+            );
+        }
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    // Seconds are not useful for cache age
+    let age = Duration::from_secs(age.as_secs() / 60 * 60);
+    if age.is_zero() {
+        return "<1m".to_string();
     }
+    format_duration(age)
 }
 
 pub fn fatal_error(code: Error, message: String, notify: bool) -> ! {
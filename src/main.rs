@@ -1,19 +1,32 @@
 #![allow(clippy::uninlined_format_args)]
 
 mod args;
+mod cache_command;
 
 use clap::Parser;
 use std::{
+    path::PathBuf,
     process,
     time::{Duration, Instant},
 };
 
-use crate::args::Args;
+use crate::args::{Args, Command};
 use everygarf::{
-    api::Api, colors::*, dates, fatal_error, format_bytes, format_duration, get_dir_size,
-    get_folder_path, Downloader, Error, SingleDownloadOptions,
+    api::{Api, LocalSource, SourceRegistry, TemplateSource},
+    archive::PageArchive,
+    cache::CacheWriter,
+    colors::*,
+    comics, dates, fatal_error, format_bytes, format_duration, get_default_cache_mirror_dir,
+    get_default_page_archive_dir, get_default_sources_file, get_dir_size, get_folder_path,
+    proxy::{self, ProxyPool, ProxyService},
+    rate_limit::RateLimiter,
+    tls::TlsConfig,
+    DownloadReport, Downloader, Error, OfflinePlan, RetrySchedule, SingleDownloadOptions,
 };
 
+/// Name for remote cache of comic, in list of cache files
+const DEFAULT_CACHE_LAYER: &str = "default";
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -28,11 +41,102 @@ async fn main() {
     let start_time = Instant::now();
     let notify_on_fail = args.notify_on_fail;
 
-    let folder = get_folder_path(args.folder.as_deref())
+    let comic = comics::get(&args.comic).unwrap_or_else(|| {
+        fatal_error(
+            Error::UnknownComic,
+            format!(
+                "Unknown comic `{}`. Available comics: {}",
+                args.comic,
+                comics::slugs().collect::<Vec<_>>().join(", "),
+            ),
+            notify_on_fail,
+        )
+    });
+
+    let mut sources = SourceRegistry::default();
+    let sources_file = match &args.sources_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None => get_default_sources_file().filter(|path| path.exists()),
+    };
+    if let Some(sources_file) = sources_file {
+        let template_sources = TemplateSource::load_file(&sources_file)
+            .unwrap_or_else(|error| fatal_error(Error::SourcesFile, error, notify_on_fail));
+        for source in template_sources {
+            sources.register(Box::new(source));
+        }
+    }
+    for name in &args.source {
+        if let Some(local_source) = LocalSource::from_name(name) {
+            let local_source = local_source
+                .unwrap_or_else(|error| fatal_error(Error::LocalSource, error, notify_on_fail));
+            sources.register(Box::new(local_source));
+        }
+    }
+    let source_chain = sources.get_chain(&args.source).unwrap_or_else(|name| {
+        fatal_error(
+            Error::UnknownSource,
+            format!(
+                "Unknown source `{}`. Available sources: {}, {}",
+                name,
+                SourceRegistry::CACHE_STEP,
+                sources.names().collect::<Vec<_>>().join(", "),
+            ),
+            notify_on_fail,
+        )
+    });
+
+    let forward_proxy = args.forward_proxy.as_deref().map(|forward_proxy| {
+        proxy::build_forward_proxy(forward_proxy, args.forward_proxy_auth.as_deref())
+            .unwrap_or_else(|error| fatal_error(Error::BadProxy, error, notify_on_fail))
+    });
+    let mut tls = TlsConfig::default().with_insecure(args.insecure);
+    if let Some(ca_cert) = &args.ca_cert {
+        tls = tls
+            .with_ca_bundle(ca_cert)
+            .unwrap_or_else(|error| fatal_error(Error::BadTls, error, notify_on_fail));
+    }
+    if let Some(client_cert) = &args.client_cert {
+        tls = tls
+            .with_client_cert(client_cert, args.client_key.as_deref())
+            .unwrap_or_else(|error| fatal_error(Error::BadTls, error, notify_on_fail));
+    }
+    if tls.is_insecure() {
+        println!("{YELLOW}{BOLD}Warning:{RESET}{YELLOW} TLS certificate verification is disabled (`--insecure`){RESET}");
+    }
+
+    if let Some(Command::Cache { command }) = &args.command {
+        let timeout = Duration::from_secs(args.timeout.into());
+        let client = everygarf::build_client(timeout, &tls, forward_proxy.as_ref()).unwrap_or_else(
+            |error| {
+                fatal_error(
+                    Error::BadTls,
+                    format!("Failed to build request client - {}", error),
+                    notify_on_fail,
+                )
+            },
+        );
+        let rate_limiter = RateLimiter::new(args.rate, &args.rate_host);
+        let options = cache_command::Options {
+            comic,
+            client: &client,
+            rate_limiter: &rate_limiter,
+            job_count: args.jobs.into(),
+            notify_on_fail,
+        };
+        cache_command::run(command, options).await;
+        return;
+    }
+
+    let folder = get_folder_path(args.folder.as_deref(), comic)
         .unwrap_or_else(|error| fatal_error(Error::NoDir, error, notify_on_fail));
     let folder_string = folder.to_string_lossy();
 
-    let start_date = args.start_from.unwrap_or(dates::first());
+    let first_date = source_chain
+        .iter()
+        .map(|step| step.first_date(comic))
+        .min()
+        .unwrap_or(comic.first_date);
+    let start_date = args.start_from.unwrap_or(first_date);
     let timeout = Duration::from_secs(args.timeout.into());
     let timeout_initial = Duration::from_secs(args.initial_timeout.into());
     let job_count: usize = args.jobs.into();
@@ -50,16 +154,19 @@ async fn main() {
         );
     }
 
-    everygarf::create_target_dir(&folder, args.remove_all)
-        .map_err(|error| {
-            format!(
-                "Failed to create or clear target directory `{}` - {:#?}",
-                folder_string, error,
-            )
-        })
-        .unwrap_or_else(|error| fatal_error(Error::CreateDir, error, notify_on_fail));
+    // Offline mode does not write anything
+    if !args.offline {
+        everygarf::create_target_dir(&folder, args.remove_all)
+            .map_err(|error| {
+                format!(
+                    "Failed to create or clear target directory `{}` - {:#?}",
+                    folder_string, error,
+                )
+            })
+            .unwrap_or_else(|error| fatal_error(Error::CreateDir, error, notify_on_fail));
+    }
 
-    let (first_date, today_date) = (dates::first(), dates::today());
+    let today_date = dates::today();
     if start_date < first_date {
         fatal_error(
             Error::BadStartDate,
@@ -82,8 +189,12 @@ async fn main() {
     }
 
     let all_dates = dates::get_dates_between(start_date, dates::latest());
-    let existing_dates = everygarf::get_existing_dates(&folder)
-        .unwrap_or_else(|error| fatal_error(Error::ReadExistingDates, error, notify_on_fail));
+    let existing_dates = if folder.exists() || !args.offline {
+        everygarf::get_existing_dates(&folder)
+            .unwrap_or_else(|error| fatal_error(Error::ReadExistingDates, error, notify_on_fail))
+    } else {
+        Vec::new()
+    };
 
     let mut missing_dates: Vec<_> = all_dates
         .into_iter()
@@ -114,29 +225,76 @@ async fn main() {
     let proxy = if args.no_proxy {
         None
     } else {
-        Some(args.proxy)
+        let proxies = args
+            .proxy
+            .iter()
+            .map(|proxy| ProxyService::new(proxy, args.proxy_header.as_deref()))
+            .collect();
+        Some(ProxyPool::new(proxies, args.proxy_fallback_direct))
     };
-    let cache_url = if args.no_cache {
-        None
+    let cache_urls: Vec<_> = if args.no_cache {
+        Vec::new()
+    } else if args.cache.is_empty() {
+        comic.cache_url.map(String::from).into_iter().collect()
+    } else {
+        // Comics without a remote cache do not have a default layer
+        args.cache
+            .iter()
+            .filter_map(|cache_url| match cache_url.as_str() {
+                DEFAULT_CACHE_LAYER => comic.cache_url.map(String::from),
+                _ => Some(cache_url.clone()),
+            })
+            .collect()
+    };
+
+    let rate_limiter = RateLimiter::new(args.rate, &args.rate_host).with_bandwidth(args.limit_rate);
+
+    let page_archive = if args.archive_pages || args.page_archive_dir.is_some() || args.reparse {
+        let folder = args
+            .page_archive_dir
+            .or_else(get_default_page_archive_dir)
+            .unwrap_or_else(|| {
+                fatal_error(
+                    Error::NoDir,
+                    "Cannot find page archive location. Please use `--page-archive-dir`"
+                        .to_string(),
+                    notify_on_fail,
+                )
+            });
+        if args.reparse {
+            println!(
+                "Re-parsing archived webpages in {UNDERLINE}{}{RESET}",
+                folder.to_string_lossy()
+            );
+        }
+        Some(PageArchive::new(folder, args.reparse))
     } else {
-        Some(args.cache)
+        // Webpages which image URL could not be found in are always kept
+        get_default_page_archive_dir().map(PageArchive::failed_only)
     };
 
     let api = Api {
-        source: args.source,
-        proxy: proxy.as_deref(),
+        sources: &source_chain,
+        comic,
+        proxy: proxy.as_ref(),
+        rate_limiter: &rate_limiter,
+        page_archive: page_archive.as_ref(),
     };
 
-    let cache_file = args.save_cache;
+    let cache_writer = args.save_cache.map(CacheWriter::new);
     let image_format = args.format.to_string();
     let always_ping = args.always_ping;
 
     let single_download_options = SingleDownloadOptions {
         attempt_count,
         api,
-        cache_file: cache_file.as_deref(),
+        cache_writer: cache_writer.as_ref(),
         image_format: image_format.as_str(),
         save_as_tree: args.tree,
+        retry: RetrySchedule {
+            base: args.retry_base,
+            max: args.retry_max,
+        },
     };
 
     let downloader = Downloader {
@@ -144,20 +302,33 @@ async fn main() {
         folder: &folder,
         dates: &missing_dates,
         job_count,
-        cache_url,
+        cache_urls,
+        cache_mirror_dir: get_default_cache_mirror_dir(),
         always_ping,
         timeout_main: timeout,
         timeout_initial,
         notify_on_fail,
+        forward_proxy,
+        tls,
     };
 
+    if args.offline {
+        let plan = downloader
+            .plan_offline()
+            .await
+            .unwrap_or_else(|error| fatal_error(Error::Offline, error, notify_on_fail));
+        print_offline_plan(&plan, real_download_count);
+        return;
+    }
+
+    let mut report = DownloadReport::default();
     if real_download_count > 0 {
         println!(
-            "Downloading {BOLD}{}{RESET} images using (up to) {BOLD}{}{RESET} concurrent jobs...{RESET}",
+            "Downloading {BOLD}{}{RESET} images using up to {BOLD}{}{RESET} concurrent jobs (adjusted automatically)...{RESET}",
             missing_dates.len(),
             job_count,
         );
-        downloader.download_all_images().await;
+        report = downloader.download_all_images().await;
     }
 
     let elapsed_time = format_duration(Duration::from_secs(start_time.elapsed().as_secs()));
@@ -175,9 +346,96 @@ async fn main() {
     }
     println!(
         " {DIM}•{RESET} Downloaded: {BOLD}{}{RESET} images",
-        real_download_count,
+        real_download_count - report.skipped.len(),
     );
+    if !report.skipped.is_empty() {
+        println!(
+            " {DIM}•{RESET} Skipped: {BOLD}{}{RESET} images {DIM}(not available from any source){RESET}",
+            report.skipped.len(),
+        );
+        for (date, reason) in &report.skipped {
+            println!(
+                "     {DIM}-{RESET} {BOLD}{}{RESET} {DIM}{}{RESET}",
+                date, reason
+            );
+        }
+    }
+    if !report.source_counts.is_empty() {
+        let source_counts: Vec<_> = report
+            .source_counts
+            .iter()
+            .map(|(source_name, count)| format!("{BOLD}{}{RESET} from {}", count, source_name))
+            .collect();
+        println!(" {DIM}•{RESET} Sources: {}", source_counts.join(", "));
+    }
+    print_cache_layer_counts(&report.cache_layer_counts);
+    if report.peak_concurrency > 0 {
+        println!(
+            " {DIM}•{RESET} Concurrency: {BOLD}{}{RESET} jobs {DIM}(peak {}){RESET}",
+            report.concurrency, report.peak_concurrency,
+        );
+    }
+    for (proxy, stats) in &report.proxy_stats {
+        if stats.request_count == 0 {
+            continue;
+        }
+        println!(
+            " {DIM}•{RESET} Proxy {UNDERLINE}{}{RESET}: {BOLD}{}{RESET} requests, {BOLD}{}%{RESET} errors, {BOLD}{}ms{RESET} average",
+            proxy,
+            stats.request_count,
+            stats.error_count * 100 / stats.request_count,
+            stats.average_latency().as_millis(),
+        );
+    }
     println!(" {DIM}•{RESET} Elapsed time: {BOLD}{}{RESET}", elapsed_time);
     println!(" {DIM}•{RESET} Total size: {BOLD}{}{RESET}", folder_size);
     println!();
 }
+
+fn print_offline_plan(plan: &OfflinePlan, download_count: usize) {
+    println!();
+    println!(
+        "{GREEN}{BOLD}Offline plan{RESET} for {BOLD}{}{RESET} missing images {DIM}(nothing was downloaded){RESET}",
+        download_count,
+    );
+    for source in &plan.sources {
+        if source.image_count == 0 {
+            continue;
+        }
+        let method = if source.name == SourceRegistry::CACHE_STEP {
+            "served from cache"
+        } else if source.requires_scraping {
+            "requires scraping"
+        } else {
+            "direct image URLs"
+        };
+        println!(
+            " {DIM}•{RESET} {}: {BOLD}{}{RESET} images, {} {DIM}(~{} requests){RESET}",
+            source.name, source.image_count, method, source.request_count,
+        );
+    }
+    print_cache_layer_counts(&plan.cache_layer_counts);
+    if !plan.unavailable.is_empty() {
+        println!(
+            " {DIM}•{RESET} Not available from any source: {BOLD}{}{RESET} images",
+            plan.unavailable.len(),
+        );
+    }
+    println!(
+        " {DIM}•{RESET} Estimated requests: {BOLD}{}{RESET}",
+        plan.request_count(),
+    );
+    println!();
+}
+
+/// Show which cache file each cached URL was from, if multiple cache files are used
+fn print_cache_layer_counts(cache_layer_counts: &[(String, usize)]) {
+    if cache_layer_counts.len() < 2 {
+        return;
+    }
+    let counts: Vec<_> = cache_layer_counts
+        .iter()
+        .map(|(cache_url, count)| format!("{BOLD}{}{RESET} from {}", count, cache_url))
+        .collect();
+    println!(" {DIM}•{RESET} Cache files: {}", counts.join(", "));
+}
@@ -0,0 +1,197 @@
+use reqwest::{Client, RequestBuilder, Response};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits requests per second to each host, and total download bandwidth, shared between all
+/// download jobs
+///
+/// Each host has a token bucket, which allows short bursts up to the rate
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Requests per second for hosts without a specific rate. `None` is unlimited
+    default_rate: Option<f64>,
+    host_rates: HashMap<String, f64>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Bytes per second, for all image downloads
+    bandwidth: Option<(f64, Mutex<Bucket>)>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// May be negative, if requests are waiting for tokens
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Rate for a specific host, given as `<host>=<requests per second>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostRate {
+    pub host: String,
+    pub rate: f64,
+}
+
+impl RateLimiter {
+    pub fn new(default_rate: Option<f64>, host_rates: &[HostRate]) -> Self {
+        Self {
+            default_rate,
+            host_rates: host_rates
+                .iter()
+                .map(|host_rate| (host_rate.host.to_lowercase(), host_rate.rate))
+                .collect(),
+            buckets: Mutex::default(),
+            bandwidth: None,
+        }
+    }
+
+    /// Limit total bytes per second of image downloads
+    pub fn with_bandwidth(mut self, bytes_per_second: Option<f64>) -> Self {
+        self.bandwidth = bytes_per_second.map(|rate| (rate, Mutex::new(Bucket::new(rate))));
+        self
+    }
+
+    pub fn limits_bandwidth(&self) -> bool {
+        self.bandwidth.is_some()
+    }
+
+    fn get_rate(&self, host: &str) -> Option<f64> {
+        self.host_rates.get(host).copied().or(self.default_rate)
+    }
+
+    /// Reserve a token for host, and get duration to wait until it is available
+    fn reserve(&self, host: &str) -> Duration {
+        let Some(rate) = self.get_rate(host) else {
+            return Duration::ZERO;
+        };
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        buckets
+            .entry(host.to_string())
+            .or_insert_with(|| Bucket::new(rate))
+            .take(1.0, rate)
+    }
+
+    /// Wait until a request can be sent to host
+    pub async fn wait_for_host(&self, host: &str) {
+        self.wait_for_hosts(&[host]).await;
+    }
+
+    /// Wait until a request can be sent to all hosts, eg. a proxy and the target of a proxied
+    /// request
+    ///
+    /// A token is taken from each host once, even if it is given twice
+    pub async fn wait_for_hosts(&self, hosts: &[&str]) {
+        let hosts: BTreeSet<_> = hosts.iter().map(|host| host.to_lowercase()).collect();
+        let wait = hosts
+            .iter()
+            .map(|host| self.reserve(host))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Send request, after waiting for its host
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let (client, request) = request.build_split();
+        let request = request?;
+        if let Some(host) = request.url().host_str() {
+            self.wait_for_host(host).await;
+        }
+        Client::execute(&client, request).await
+    }
+
+    /// Wait until amount of downloaded bytes is allowed by bandwidth limit
+    pub async fn wait_for_bytes(&self, byte_count: usize) {
+        let Some((rate, bucket)) = &self.bandwidth else {
+            return;
+        };
+        let wait = bucket
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .take(byte_count as f64, *rate);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Bucket {
+    /// Allow burst of one second worth of tokens
+    fn new(rate: f64) -> Self {
+        Self {
+            tokens: Self::get_capacity(rate),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn get_capacity(rate: f64) -> f64 {
+        rate.max(1.0)
+    }
+
+    /// Reserve tokens, and get duration to wait until they are available
+    fn take(&mut self, amount: f64, rate: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(Self::get_capacity(rate)) - amount;
+        self.last_refill = now;
+
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / rate)
+    }
+}
+
+impl FromStr for HostRate {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let Some((host, rate)) = string.split_once('=') else {
+            return Err("Expected `<host>=<requests per second>`".to_string());
+        };
+        let host = host.trim();
+        if host.is_empty() {
+            return Err("Missing host".to_string());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            rate: parse_rate(rate.trim())?,
+        })
+    }
+}
+
+/// Parse bytes per second, with optional `k`, `m`, or `g` suffix (powers of 1024)
+///
+/// Eg. `500k`, `1.5M`
+pub fn parse_bandwidth(string: &str) -> Result<f64, String> {
+    let trimmed = string.trim();
+    let (number, multiplier) = match trimmed.chars().last().map(|ch| ch.to_ascii_lowercase()) {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1024.0),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1024.0 * 1024.0),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (trimmed, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number.is_finite() && number > 0.0 => Ok(number * multiplier),
+        _ => Err(format!(
+            "Invalid rate `{}`. Must be a positive number of bytes per second, eg. `500k`",
+            string
+        )),
+    }
+}
+
+/// Parse positive requests per second
+pub fn parse_rate(string: &str) -> Result<f64, String> {
+    match string.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!(
+            "Invalid rate `{}`. Must be a positive number of requests per second",
+            string
+        )),
+    }
+}
@@ -71,3 +71,1459 @@ fn format_duration_works() {
     assert_eq!(format_duration(Duration::from_secs(864_000)), "10d");
     assert_eq!(format_duration(Duration::from_secs(8_643_600)), "100d 1h");
 }
+
+#[test]
+fn source_registry_works() {
+    let sources = api::SourceRegistry::default();
+    assert!(sources.get("gocomics").is_some());
+    assert!(sources.get("GoComics").is_some());
+    assert!(sources.get("unknown").is_none());
+
+    let source = sources.get("gocomics").unwrap();
+    let url = "https://featureassets.gocomics.com/assets/0123456789abcdef0123456789abcdef";
+    let minified = source.minify_image_url(url);
+    assert_eq!(minified, "0123456789abcdef0123456789abcdef");
+    assert_eq!(source.expand_image_url(minified), url);
+    assert_eq!(source.expand_image_url(url), url);
+}
+
+#[test]
+fn gocomics_find_image_url_works() {
+    use api::{ExtractError, Gocomics, Source};
+    let expected = "https://featureassets.gocomics.com/assets/0123456789abcdef0123456789abcdef";
+
+    let body = r#"<head><meta property="og:image" content="https://featureassets.gocomics.com/assets/0123456789abcdef0123456789abcdef?optimizer=image&amp;width=1200"></head>"#;
+    assert_eq!(
+        Gocomics
+            .find_image_url("https://www.gocomics.com/garfield/2000/01/01", body)
+            .unwrap(),
+        expected
+    );
+
+    let body = r#"<script type="application/ld+json">{"@type":"ImageObject","image":{"url":"https://featureassets.gocomics.com/assets/0123456789abcdef0123456789abcdef"}}</script>"#;
+    assert_eq!(
+        Gocomics
+            .find_image_url("https://www.gocomics.com/garfield/2000/01/01", body)
+            .unwrap(),
+        expected
+    );
+
+    let body = r#"<picture><source srcset="https://featureassets.gocomics.com/assets/0123456789abcdef0123456789abcdef 1x"><img src="/placeholder.png"></picture>"#;
+    assert_eq!(
+        Gocomics
+            .find_image_url("https://www.gocomics.com/garfield/2000/01/01", body)
+            .unwrap(),
+        expected
+    );
+
+    let body = r#"<meta property="og:image" content="https://www.gocomics.com/logo.png">"#;
+    assert!(matches!(
+        Gocomics.find_image_url("https://www.gocomics.com/garfield/2000/01/01", body),
+        Err(ExtractError::InvalidUrl(_))
+    ));
+
+    let body = "<html><body>Redesigned!</body></html>";
+    assert!(matches!(
+        Gocomics.find_image_url("https://www.gocomics.com/garfield/2000/01/01", body),
+        Err(ExtractError::LayoutChanged)
+    ));
+}
+
+#[test]
+fn source_chain_works() {
+    let sources = api::SourceRegistry::default();
+    let names = ["cache".to_string(), "gocomics".to_string()];
+    let chain = sources.get_chain(&names).unwrap();
+    let chain_names: Vec<_> = chain.iter().map(|step| step.name()).collect();
+    assert_eq!(chain_names, ["cache", "gocomics"]);
+
+    let names = ["gocomics".to_string(), "unknown".to_string()];
+    assert_eq!(sources.get_chain(&names).unwrap_err(), "unknown");
+}
+
+#[test]
+fn uclick_source_works() {
+    use api::{Lookup, Source, Uclick};
+
+    let date = chrono::NaiveDate::from_ymd_opt(1978, 6, 19).unwrap();
+    assert_eq!(
+        Uclick.lookup(&comics::GARFIELD, date),
+        Lookup::Image("https://picayune.uclick.com/comics/ga/1978/ga780619.gif".to_string())
+    );
+    assert_eq!(Uclick.lookup(&comics::US_ACRES, date), Lookup::Unavailable);
+
+    let strip = encode_gif(600, 180);
+    let placeholder = encode_gif(20, 20);
+
+    assert!(Uclick.validate_image(Some("image/gif"), &strip).is_ok());
+    assert!(Uclick
+        .validate_image(Some("image/gif"), &placeholder)
+        .is_err());
+    assert!(Uclick.validate_image(Some("text/html"), &strip).is_err());
+    assert!(Uclick.validate_image(Some("image/gif"), b"<html>").is_err());
+}
+
+#[tokio::test]
+async fn cache_before_uclick_reads_minified_rows() {
+    use api::{Gocomics, SourceStep, Uclick};
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-chain-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let cache_file = folder.join("cache");
+    let cache_path = cache_file.to_string_lossy().to_string();
+    let gocomics_url = "https://featureassets.gocomics.com/assets/0123456789abcdef";
+    let uclick_url = "https://picayune.uclick.com/comics/ga/1978/ga780619.gif";
+    let dates = [
+        NaiveDate::from_ymd_opt(1978, 6, 19).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+    ];
+
+    // URLs are minified the same way, whichever sources are used
+    let writer = cache::CacheWriter::new(&cache_file);
+    for (date, url) in dates.iter().zip([uclick_url, gocomics_url]) {
+        let entry = cache::CacheEntry {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        writer.append(*date, &entry);
+    }
+    writer.finish().unwrap();
+    let file = fs::read_to_string(&cache_file).unwrap();
+    assert!(file.contains(&format!("1978-06-19 {}\n", uclick_url)));
+    assert!(file.contains("2001-09-10 0123456789abcdef\n"));
+
+    let (cached, _) = cache::fetch_cached_urls(
+        &Client::new(),
+        &rate_limit::RateLimiter::default(),
+        &cache_path,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(cached[&dates[0]].url, uclick_url);
+    assert_eq!(cached[&dates[1]].url, gocomics_url);
+
+    // Both rows are used before uclick
+    let sources = [
+        SourceStep::Cache,
+        SourceStep::Source(&Uclick),
+        SourceStep::Source(&Gocomics),
+    ];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader =
+        test_downloader(&sources, &comics::GARFIELD, &rate_limiter, &folder, &dates);
+    downloader.cache_urls = vec![cache_path];
+    let plan = downloader.plan_offline().await.unwrap();
+    assert_eq!(plan.sources[0].image_count, 2);
+    assert_eq!(plan.sources[1].image_count, 0);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn template_source_works() {
+    use api::{Lookup, Source, TemplateSource};
+
+    let file = r#"
+        [[source]]
+        name = "mirror"
+        page_url = "https://mirror.example.com/{comic}/{yyyy}/{mm}/{dd}/{yy}"
+        first_date = "2000-01-01"
+        regex = '"(https://cdn\.example\.com/[a-z0-9]+\.gif)"'
+
+        [[source]]
+        name = "archive"
+        page_url = "https://archive.example.com/{yyyy}{mm}{dd}"
+        selector = 'img.strip[data-kind="daily"]'
+        attribute = "src"
+    "#;
+    let sources = TemplateSource::parse_file(file).unwrap();
+    assert_eq!(sources.len(), 2);
+
+    let date = chrono::NaiveDate::from_ymd_opt(2001, 9, 5).unwrap();
+    assert_eq!(
+        sources[0].lookup(&comics::GARFIELD, date),
+        Lookup::Page("https://mirror.example.com/garfield/2001/09/05/01".to_string())
+    );
+    assert_eq!(
+        sources[0].first_date(&comics::GARFIELD).to_string(),
+        "2000-01-01"
+    );
+    assert_eq!(
+        sources[1].first_date(&comics::GARFIELD),
+        comics::GARFIELD.first_date
+    );
+
+    let body = r#"<img src="https://cdn.example.com/abc123.gif">"#;
+    assert_eq!(
+        sources[0]
+            .find_image_url("https://mirror.example.com/garfield/2001/09/05/01", body)
+            .unwrap(),
+        "https://cdn.example.com/abc123.gif"
+    );
+
+    let body = r#"<img class="ad" src="https://ads.example.com/x.gif">
+        <img class="comic strip" data-kind="daily" src="https://archive.example.com/img/1.gif">"#;
+    assert_eq!(
+        sources[1]
+            .find_image_url("https://archive.example.com/20010905", body)
+            .unwrap(),
+        "https://archive.example.com/img/1.gif"
+    );
+
+    let file = r#"
+        [[source]]
+        name = "cache"
+        page_url = "https://example.com"
+        regex = "x"
+    "#;
+    assert!(TemplateSource::parse_file(file).is_err());
+
+    let file = r#"
+        [[source]]
+        name = "both"
+        page_url = "https://example.com"
+        regex = "x"
+        selector = "img"
+        attribute = "src"
+    "#;
+    assert!(TemplateSource::parse_file(file).is_err());
+    // Non-ASCII character after a filter
+    let file = r#"
+        [[source]]
+        name = "invalid"
+        page_url = "https://example.com"
+        selector = "img[src]é"
+        attribute = "src"
+    "#;
+    assert!(TemplateSource::parse_file(file).is_err());
+    assert!("img.strip[src]é".parse::<html::Selector>().is_err());
+}
+
+#[test]
+fn local_source_works() {
+    use api::{LocalSource, Lookup, Source};
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-local-{}", process::id()));
+    fs::create_dir_all(folder.join("1999/02")).unwrap();
+    fs::write(folder.join("1980-01-01.gif"), b"").unwrap();
+    fs::write(folder.join("1999/02/03.png"), b"").unwrap();
+    fs::write(folder.join("notes.txt"), b"").unwrap();
+    // Stray files in tree layout are skipped
+    fs::write(folder.join("1999/05"), b"").unwrap();
+    fs::write(folder.join("2000"), b"").unwrap();
+
+    assert!(LocalSource::from_name("gocomics").is_none());
+    let name = folder.to_string_lossy();
+    let source = LocalSource::from_name(&name).unwrap().unwrap();
+
+    let lookup = |y, m, d| {
+        let date = chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        source.lookup(&comics::GARFIELD, date)
+    };
+    let Lookup::Image(url) = lookup(1980, 1, 1) else {
+        panic!("flat layout image not found");
+    };
+    assert!(url.starts_with("file://") && url.ends_with("/1980-01-01.gif"));
+    let Lookup::Image(url) = lookup(1999, 2, 3) else {
+        panic!("tree layout image not found");
+    };
+    assert!(url.ends_with("/1999/02/03.png"));
+    assert_eq!(lookup(2000, 1, 1), Lookup::Unavailable);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+/// Serve fixed responses on a local port, as a stand-in for a remote server
+///
+/// Routes are matched by path prefix (including query). Returns base URL
+fn serve_stand_in(routes: Vec<(&'static str, &'static str, Vec<u8>)>) -> String {
+    serve_stand_in_limited(routes, usize::MAX)
+}
+
+/// Stand-in server which stops after an amount of requests
+///
+/// Responses include an `ETag`, and requests with `If-None-Match` receive `304 Not Modified`
+fn serve_stand_in_limited(
+    routes: Vec<(&'static str, &'static str, Vec<u8>)>,
+    max_requests: usize,
+) -> String {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten().take(max_requests) {
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+            let mut is_revalidation = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|len| len > 2) {
+                is_revalidation |= line.to_lowercase().starts_with("if-none-match:");
+                line.clear();
+            }
+
+            let route = routes
+                .iter()
+                .find(|(prefix, _, _)| path.starts_with(prefix));
+            let (status, content_type, body) = match route {
+                Some((_, content_type, _)) if is_revalidation => {
+                    ("304 Not Modified", *content_type, &b""[..])
+                }
+                Some((_, content_type, body)) => ("200 OK", *content_type, body.as_slice()),
+                None => ("404 Not Found", "text/plain", &b""[..]),
+            };
+            let mut stream = &stream;
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nETag: \"stand-in\"\r\nConnection: close\r\n\r\n",
+                body.len(),
+            );
+            let _ = stream.write_all(body);
+        }
+    });
+    base_url
+}
+
+/// Blank image, encoded as GIF
+fn encode_gif(width: u32, height: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image::DynamicImage::new_rgb8(width, height)
+        .write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Gif,
+        )
+        .unwrap();
+    bytes
+}
+
+/// Downloader with a single attempt and job, and without cache files or proxies
+fn test_downloader<'a, 'b, 'c>(
+    sources: &'a [api::SourceStep<'a>],
+    comic: &'a comics::Comic,
+    rate_limiter: &'a rate_limit::RateLimiter,
+    folder: &'b Path,
+    dates: &'c [NaiveDate],
+) -> Downloader<'a, 'b, 'c> {
+    Downloader {
+        single_download_options: SingleDownloadOptions {
+            attempt_count: 1,
+            api: Api {
+                sources,
+                comic,
+                proxy: None,
+                rate_limiter,
+                page_archive: None,
+            },
+            cache_writer: None,
+            image_format: "gif",
+            retry: RetrySchedule::default(),
+            save_as_tree: false,
+        },
+        folder,
+        dates,
+        job_count: 1,
+        cache_urls: Vec::new(),
+        cache_mirror_dir: None,
+        always_ping: false,
+        timeout_main: Duration::from_secs(5),
+        timeout_initial: Duration::from_secs(5),
+        notify_on_fail: false,
+        forward_proxy: None,
+        tls: tls::TlsConfig::default(),
+    }
+}
+
+#[test]
+fn wayback_read_search_results_works() {
+    use api::{Lookup, Source, Wayback};
+
+    let wayback = Wayback::new("http://localhost/");
+    let date = chrono::NaiveDate::from_ymd_opt(2001, 9, 10).unwrap();
+    assert_eq!(
+        wayback.lookup(&comics::GARFIELD, date),
+        Lookup::Search("http://localhost/cdx/search/cdx?url=www.gocomics.com/garfield/2001/09/10&output=json&filter=statuscode:200&closest=20010910&sort=closest&limit=1".to_string())
+    );
+
+    let results = r#"[["urlkey","timestamp","original"],["x","20011001000000","https://www.gocomics.com/garfield/2001/09/10"]]"#;
+    assert_eq!(
+        wayback
+            .read_search_results(&comics::GARFIELD, date, results)
+            .unwrap(),
+        Lookup::Page(
+            "http://localhost/web/20011001000000id_/https://www.gocomics.com/garfield/2001/09/10"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        wayback
+            .read_search_results(&comics::GARFIELD, date, "")
+            .unwrap(),
+        Lookup::Unavailable
+    );
+    assert_eq!(
+        wayback
+            .read_search_results(&comics::GARFIELD, date, "[]")
+            .unwrap(),
+        Lookup::Unavailable
+    );
+    assert!(wayback
+        .read_search_results(&comics::GARFIELD, date, "<html>")
+        .is_err());
+}
+
+#[tokio::test]
+async fn wayback_source_downloads_from_stand_in() {
+    use api::{SourceStep, Wayback};
+
+    let gif = encode_gif(600, 180);
+
+    let base_url = serve_stand_in(vec![
+        (
+            "/cdx/search/cdx?url=www.gocomics.com/garfield/2001/09/10&",
+            "text/plain",
+            br#"[["urlkey","timestamp","original"],["x","20011001000000","https://www.gocomics.com/garfield/2001/09/10"]]"#.to_vec(),
+        ),
+        (
+            "/web/20011001000000id_/https://www.gocomics.com/garfield/2001/09/10",
+            "text/html",
+            br#"<meta property="og:image" content="https://assets.amuniversal.com/0123456789abcdef">"#.to_vec(),
+        ),
+        (
+            "/web/20011001000000im_/https://assets.amuniversal.com/0123456789abcdef",
+            "image/gif",
+            gif,
+        ),
+    ]);
+
+    let wayback = Wayback::new(base_url);
+    let sources = [SourceStep::Source(&wayback)];
+    let folder = std::env::temp_dir().join(format!("everygarf-test-wayback-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    // Second date is not archived, so is skipped without retrying
+    let dates = [
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+    ];
+
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader =
+        test_downloader(&sources, &comics::GARFIELD, &rate_limiter, &folder, &dates);
+    downloader.single_download_options.attempt_count = 3;
+    let report = downloader.download_all_images().await;
+
+    assert_eq!(report.source_counts.get("wayback"), Some(&1));
+    assert!(folder.join("2001-09-10.gif").is_file());
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].0, dates[1]);
+    assert!(report.skipped[0].1.contains("404"));
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn proxy_service_works() {
+    use proxy::ProxyService;
+
+    let client = Client::new();
+    let target = "https://www.gocomics.com/garfield/2001/09/10";
+    let build = |proxy: &ProxyService| proxy.build_request(&client, target).build().unwrap();
+
+    let proxy = ProxyService::new("https://proxy.example.com/cors-proxy", None);
+    assert_eq!(
+        build(&proxy).url().as_str(),
+        "https://proxy.example.com/cors-proxy?https://www.gocomics.com/garfield/2001/09/10"
+    );
+
+    let proxy = ProxyService::new("https://proxy.example.com/fetch?target={url_encoded}", None);
+    assert!(matches!(proxy, ProxyService::Template(_)));
+    assert_eq!(
+        build(&proxy).url().as_str(),
+        "https://proxy.example.com/fetch?target=https%3A%2F%2Fwww%2Egocomics%2Ecom%2Fgarfield%2F2001%2F09%2F10"
+    );
+
+    let proxy = ProxyService::new("https://proxy.example.com/{url}", None);
+    assert_eq!(
+        build(&proxy).url().as_str(),
+        "https://proxy.example.com/https://www.gocomics.com/garfield/2001/09/10"
+    );
+
+    let proxy = ProxyService::new("https://proxy.example.com/", Some("X-Target-Url"));
+    let request = build(&proxy);
+    assert_eq!(request.url().as_str(), "https://proxy.example.com/");
+    assert_eq!(request.headers()["X-Target-Url"], target);
+
+    assert!(proxy::build_forward_proxy("socks5://127.0.0.1:1080", Some("user:pass")).is_ok());
+    assert!(proxy::build_forward_proxy("http://127.0.0.1:3128", Some("nopassword")).is_err());
+    assert!(proxy::build_forward_proxy("ftp://127.0.0.1", None).is_err());
+}
+
+#[test]
+fn proxy_pool_works() {
+    use proxy::{ProxyPool, ProxyService};
+    use reqwest::StatusCode;
+
+    let client = Client::new();
+    let target = "https://www.gocomics.com/garfield/2001/09/10";
+    let proxies = vec![
+        ProxyService::new("https://a.example.com/", None),
+        ProxyService::new("https://b.example.com/", None),
+    ];
+    let host = |request: reqwest::RequestBuilder| {
+        request
+            .build()
+            .unwrap()
+            .url()
+            .host_str()
+            .unwrap()
+            .to_string()
+    };
+
+    // Round robin
+    let pool = ProxyPool::new(proxies.clone(), false);
+    let (request, ticket_a) = pool.build_request(&client, target);
+    assert_eq!(host(request), "a.example.com");
+    let (request, ticket_b) = pool.build_request(&client, target);
+    assert_eq!(host(request), "b.example.com");
+
+    // Rate limited proxy is ejected
+    pool.report(
+        ticket_a.unwrap(),
+        Some(StatusCode::TOO_MANY_REQUESTS),
+        Duration::ZERO,
+    );
+    pool.report(ticket_b.unwrap(), Some(StatusCode::OK), Duration::ZERO);
+    for _ in 0..3 {
+        let (request, _) = pool.build_request(&client, target);
+        assert_eq!(host(request), "b.example.com");
+    }
+    let stats = pool.stats();
+    assert_eq!((stats[0].1.request_count, stats[0].1.error_count), (1, 1));
+    assert_eq!((stats[1].1.request_count, stats[1].1.error_count), (1, 0));
+
+    // All proxies ejected
+    let (_, ticket) = pool.build_request(&client, target);
+    pool.report(ticket.unwrap(), None, Duration::ZERO);
+    let (request, ticket) = pool.build_request(&client, target);
+    assert!(ticket.is_some());
+    assert!(host(request).ends_with("example.com"));
+
+    let pool = ProxyPool::new(proxies, true);
+    for _ in 0..2 {
+        let (_, ticket) = pool.build_request(&client, target);
+        pool.report(
+            ticket.unwrap(),
+            Some(StatusCode::BAD_GATEWAY),
+            Duration::ZERO,
+        );
+    }
+    let (request, ticket) = pool.build_request(&client, target);
+    assert!(ticket.is_none());
+    assert_eq!(host(request), "www.gocomics.com");
+}
+
+#[tokio::test]
+async fn rate_limiter_works() {
+    use rate_limit::{HostRate, RateLimiter};
+    use std::time::Instant;
+
+    assert_eq!(
+        "www.gocomics.com=2.5".parse::<HostRate>(),
+        Ok(HostRate {
+            host: "www.gocomics.com".to_string(),
+            rate: 2.5,
+        })
+    );
+    assert!("www.gocomics.com".parse::<HostRate>().is_err());
+    assert!("www.gocomics.com=0".parse::<HostRate>().is_err());
+    assert!("=2".parse::<HostRate>().is_err());
+
+    let limiter = RateLimiter::new(None, &["slow.example.com=20".parse::<HostRate>().unwrap()]);
+
+    // Unlimited host
+    let start_time = Instant::now();
+    for _ in 0..100 {
+        limiter.wait_for_host("fast.example.com").await;
+    }
+    assert!(start_time.elapsed() < Duration::from_millis(50));
+
+    // Burst, then limited
+    let start_time = Instant::now();
+    for _ in 0..20 {
+        limiter.wait_for_host("SLOW.example.com").await;
+    }
+    assert!(start_time.elapsed() < Duration::from_millis(50));
+    for _ in 0..5 {
+        limiter.wait_for_host("slow.example.com").await;
+    }
+    assert!(start_time.elapsed() >= Duration::from_millis(200));
+
+    // Proxied request waits for target host, as well as proxy
+    let start_time = Instant::now();
+    limiter
+        .wait_for_hosts(&["fast.example.com", "slow.example.com"])
+        .await;
+    assert!(start_time.elapsed() >= Duration::from_millis(40));
+}
+
+#[tokio::test]
+async fn bandwidth_limit_works() {
+    use rate_limit::{parse_bandwidth, RateLimiter};
+    use std::time::Instant;
+
+    assert_eq!(parse_bandwidth("500k"), Ok(500.0 * 1024.0));
+    assert_eq!(parse_bandwidth("1.5M"), Ok(1.5 * 1024.0 * 1024.0));
+    assert_eq!(parse_bandwidth("2048"), Ok(2048.0));
+    assert!(parse_bandwidth("k").is_err());
+    assert!(parse_bandwidth("-1k").is_err());
+
+    let limiter = RateLimiter::default().with_bandwidth(Some(100_000.0));
+    let start_time = Instant::now();
+    // One second burst, then limited
+    for _ in 0..10 {
+        limiter.wait_for_bytes(10_000).await;
+    }
+    assert!(start_time.elapsed() < Duration::from_millis(50));
+    for _ in 0..3 {
+        limiter.wait_for_bytes(10_000).await;
+    }
+    assert!(start_time.elapsed() >= Duration::from_millis(250));
+
+    // Unlimited
+    let limiter = RateLimiter::default();
+    let start_time = Instant::now();
+    limiter.wait_for_bytes(usize::MAX).await;
+    assert!(start_time.elapsed() < Duration::from_millis(50));
+
+    // Waiting for bandwidth does not count towards request timeout
+    // Padded, so image is read in many chunks
+    let mut gif = encode_gif(600, 180);
+    gif.resize(1024 * 1024, 0);
+    let limiter = RateLimiter::default().with_bandwidth(Some(gif.len() as f64 / 2.0));
+    let base_url = serve_stand_in(vec![("/image", "image/gif", gif)]);
+    let folder = std::env::temp_dir().join(format!("everygarf-test-bandwidth-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let cache_file = folder.join("cache");
+    fs::write(&cache_file, format!("2001-09-10 {}/image\n", base_url)).unwrap();
+    let sources = [api::SourceStep::Cache];
+    let dates = [NaiveDate::from_ymd_opt(2001, 9, 10).unwrap()];
+    let mut downloader = test_downloader(&sources, &comics::GARFIELD, &limiter, &folder, &dates);
+    downloader.cache_urls = vec![cache_file.to_string_lossy().to_string()];
+    downloader.timeout_main = Duration::from_millis(500);
+    let start_time = Instant::now();
+    let report = downloader.download_all_images().await;
+    assert_eq!(report.source_counts.get("cache"), Some(&1));
+    assert!(start_time.elapsed() >= Duration::from_millis(500));
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn concurrency_controller_works() {
+    use concurrency::ConcurrencyController;
+
+    let controller = ConcurrencyController::new(20);
+    assert_eq!(controller.limit(), 4);
+
+    // Slow start
+    for _ in 0..4 {
+        controller.record_success(Duration::from_millis(100));
+    }
+    assert_eq!(controller.limit(), 8);
+
+    // High latency holds concurrency
+    controller.record_success(Duration::from_millis(1000));
+    assert_eq!(controller.limit(), 8);
+
+    // Multiplicative decrease, once per burst of failures
+    controller.record_overload();
+    controller.record_overload();
+    assert_eq!(controller.limit(), 4);
+
+    // Additive increase
+    for _ in 0..4 {
+        controller.record_success(Duration::from_millis(100));
+    }
+    assert_eq!(controller.limit(), 4);
+    controller.record_success(Duration::from_millis(100));
+    assert_eq!(controller.limit(), 5);
+    assert_eq!(controller.peak(), 8);
+
+    // Never above maximum
+    let controller = ConcurrencyController::new(2);
+    for _ in 0..10 {
+        controller.record_success(Duration::from_millis(100));
+    }
+    assert_eq!(controller.limit(), 2);
+}
+
+#[test]
+fn retry_schedule_works() {
+    let retry = RetrySchedule {
+        base: Duration::from_secs(1),
+        max: Duration::from_secs(10),
+    };
+    for _ in 0..20 {
+        let delay = retry.get_delay(1, None);
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+        let delay = retry.get_delay(3, None);
+        assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        let delay = retry.get_delay(100, None);
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+    }
+    assert_eq!(
+        retry.get_delay(1, Some(Duration::from_secs(8))),
+        Duration::from_secs(8)
+    );
+    // Retry-After is limited to maximum delay
+    assert_eq!(
+        retry.get_delay(1, Some(Duration::from_secs(86400))),
+        Duration::from_secs(10)
+    );
+}
+
+#[test]
+fn tls_config_works() {
+    use tls::TlsConfig;
+
+    // Self-signed test certificate
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBijCCAS+gAwIBAgIUY4dcx88KrfVF0wg9vka3bUbfvBcwCgYIKoZIzj0EAwIw
+GTEXMBUGA1UEAwwOZXZlcnlnYXJmLXRlc3QwIBcNMjYxMDE4MDYxMTQ4WhgPMjEy
+NjA5MjQwNjExNDhaMBkxFzAVBgNVBAMMDmV2ZXJ5Z2FyZi10ZXN0MFkwEwYHKoZI
+zj0CAQYIKoZIzj0DAQcDQgAELSOAySwtxYQ/QUHRC1h4N2o12csgfMmyX21SuSjJ
+MSmS3ZrJknfP1/85cx8rno6D8c7s89CzqRxnkTw82YK8x6NTMFEwHQYDVR0OBBYE
+FPohge90ZwE81NWWpSIc7eFTi5hPMB8GA1UdIwQYMBaAFPohge90ZwE81NWWpSIc
+7eFTi5hPMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSQAwRgIhAIvdTv0t
+gaeJx2wNrtZ4m+De5lWsooJJd0Gxabr5yckAAiEArvAf6GIOTyBFw42Th6kxAbYV
+4ngjFEvB9C8+nJ8v27M=
+-----END CERTIFICATE-----
+";
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-tls-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let bundle = folder.join("bundle.pem");
+    let not_pem = folder.join("not-pem.txt");
+    fs::write(&bundle, CERTIFICATE.repeat(2)).unwrap();
+    fs::write(&not_pem, "not a certificate").unwrap();
+
+    let tls = TlsConfig::default().with_ca_bundle(&bundle).unwrap();
+    assert!(tls.apply(Client::builder()).build().is_ok());
+    assert!(TlsConfig::default().with_ca_bundle(&not_pem).is_err());
+    assert!(TlsConfig::default()
+        .with_ca_bundle(&folder.join("missing.pem"))
+        .is_err());
+    assert!(TlsConfig::default()
+        .with_client_cert(&not_pem, None)
+        .is_err());
+
+    let tls = TlsConfig::default().with_insecure(true);
+    assert!(tls.is_insecure());
+    assert!(tls.apply(Client::builder()).build().is_ok());
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn offline_plan_works() {
+    use api::{Gocomics, SourceStep, Uclick};
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-offline-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let cache_file = folder.join("cache");
+    fs::write(&cache_file, "2001-09-10 0123456789abcdef\n").unwrap();
+
+    let sources = [
+        SourceStep::Cache,
+        SourceStep::Source(&Uclick),
+        SourceStep::Source(&Gocomics),
+    ];
+    let dates = [
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+    ];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader =
+        test_downloader(&sources, &comics::US_ACRES, &rate_limiter, &folder, &dates);
+    downloader.cache_urls = vec![cache_file.to_string_lossy().to_string()];
+
+    // Uclick only has garfield, so is skipped
+    let plan = downloader.plan_offline().await.unwrap();
+    let counts: Vec<_> = plan
+        .sources
+        .iter()
+        .map(|source| {
+            (
+                source.name.as_str(),
+                source.image_count,
+                source.request_count,
+            )
+        })
+        .collect();
+    assert_eq!(
+        counts,
+        [("cache", 1, 1), ("uclick", 0, 0), ("gocomics", 1, 2)]
+    );
+    assert!(plan.sources[2].requires_scraping);
+    assert!(plan.unavailable.is_empty());
+    assert_eq!(plan.request_count(), 3);
+
+    // Remote cache which has not been mirrored is skipped, unless no cache can be used
+    downloader.cache_urls.push(CACHE_DEFAULT.to_string());
+    let plan = downloader.plan_offline().await.unwrap();
+    assert_eq!(plan.sources[0].image_count, 1);
+    assert_eq!(plan.cache_layer_counts[0].1, 1);
+    assert_eq!(plan.cache_layer_counts[1], (CACHE_DEFAULT.to_string(), 0));
+    downloader.cache_urls.remove(0);
+    assert!(downloader.plan_offline().await.is_err());
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn page_archive_reparse_works() {
+    use api::{SourceStep, Wayback};
+    use archive::PageArchive;
+
+    let gif = encode_gif(600, 180);
+    let search_route = (
+        "/cdx/search/cdx?url=www.gocomics.com/garfield/2001/09/10&",
+        "text/plain",
+        br#"[["urlkey","timestamp","original"],["x","20011001000000","https://www.gocomics.com/garfield/2001/09/10"]]"#.to_vec(),
+    );
+    let image_route = (
+        "/web/20011001000000im_/https://assets.amuniversal.com/0123456789abcdef",
+        "image/gif",
+        gif,
+    );
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-archive-{}", process::id()));
+    let images_folder = folder.join("images");
+    fs::create_dir_all(&images_folder).unwrap();
+    let dates = [NaiveDate::from_ymd_opt(2001, 9, 10).unwrap()];
+    let rate_limiter = rate_limit::RateLimiter::default();
+
+    let download = |base_url: String, reparse: bool| {
+        let folder = &folder;
+        let images_folder = &images_folder;
+        let rate_limiter = &rate_limiter;
+        async move {
+            let wayback = Wayback::new(base_url);
+            let sources = [SourceStep::Source(&wayback)];
+            let page_archive = PageArchive::new(folder.join("pages"), reparse);
+            let mut downloader = test_downloader(
+                &sources,
+                &comics::GARFIELD,
+                rate_limiter,
+                images_folder,
+                &dates,
+            );
+            downloader.single_download_options.api.page_archive = Some(&page_archive);
+            downloader.download_all_images().await
+        }
+    };
+
+    // Webpage is archived
+    let base_url = serve_stand_in(vec![
+        search_route,
+        (
+            "/web/20011001000000id_/https://www.gocomics.com/garfield/2001/09/10",
+            "text/html",
+            br#"<meta property="og:image" content="https://assets.amuniversal.com/0123456789abcdef">"#.to_vec(),
+        ),
+        image_route.clone(),
+    ]);
+    let report = download(base_url, false).await;
+    assert_eq!(report.source_counts.get("wayback"), Some(&1));
+    assert!(folder
+        .join("pages/garfield/wayback/2001-09-10.html.gz")
+        .is_file());
+    assert!(folder
+        .join("pages/garfield/wayback/2001-09-10.search.gz")
+        .is_file());
+
+    // Search results and webpage are no longer served, but archived responses are used
+    fs::remove_file(images_folder.join("2001-09-10.gif")).unwrap();
+    let base_url = serve_stand_in(vec![image_route]);
+    let report = download(base_url, true).await;
+    assert_eq!(report.source_counts.get("wayback"), Some(&1));
+    assert!(images_folder.join("2001-09-10.gif").is_file());
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn failed_page_is_archived() {
+    use api::{SourceStep, TemplateSource};
+    use archive::PageArchive;
+
+    let image_base_url = serve_stand_in(vec![("/image", "image/gif", encode_gif(600, 180))]);
+    let page = format!(r#"<img src="{}/image">"#, image_base_url);
+    let page_base_url = serve_stand_in(vec![
+        ("/page/20010910", "text/html", page.into_bytes()),
+        ("/page/20010911", "text/html", b"<p>Redesigned</p>".to_vec()),
+    ]);
+    let template = TemplateSource::parse_file(&format!(
+        r#"
+        [[source]]
+        name = "mirror"
+        page_url = "{}/page/{{yyyy}}{{mm}}{{dd}}"
+        regex = '"(http://[^"]+)"'
+        "#,
+        page_base_url,
+    ))
+    .unwrap()
+    .remove(0);
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-failed-{}", process::id()));
+    let images_folder = folder.join("images");
+    fs::create_dir_all(&images_folder).unwrap();
+    let sources = [SourceStep::Source(&template)];
+    let dates = [
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+    ];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let page_archive = PageArchive::failed_only(folder.join("pages"));
+    let mut downloader = test_downloader(
+        &sources,
+        &comics::GARFIELD,
+        &rate_limiter,
+        &images_folder,
+        &dates,
+    );
+    downloader.single_download_options.api.page_archive = Some(&page_archive);
+    let report = downloader.download_all_images().await;
+    assert_eq!(report.source_counts.get("mirror"), Some(&1));
+    assert_eq!(report.skipped.len(), 1);
+
+    // Only webpage without image URL is kept
+    let pages_folder = folder.join("pages/garfield/mirror");
+    assert!(!pages_folder.join("2001-09-10.html.gz").exists());
+    assert!(pages_folder.join("2001-09-11.html.gz").is_file());
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn cache_mirror_works() {
+    use cache::CacheStatus;
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-mirror-{}", process::id()));
+    let base_url = serve_stand_in_limited(
+        vec![(
+            "/cache",
+            "text/plain",
+            b"2001-09-10 0123456789abcdef\n".to_vec(),
+        )],
+        2,
+    );
+    let cache_url = format!("{}/cache", base_url);
+    let client = Client::new();
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let fetch = || cache::fetch_cached_urls(&client, &rate_limiter, &cache_url, Some(&folder));
+
+    assert!(cache::read_mirrored_urls(&cache_url, &folder).is_none());
+
+    let (dates, status) = fetch().await.unwrap();
+    assert_eq!(dates.len(), 1);
+    assert!(matches!(status, CacheStatus::Downloaded));
+
+    let (dates, status) = fetch().await.unwrap();
+    assert_eq!(dates.len(), 1);
+    assert!(matches!(status, CacheStatus::NotModified { .. }));
+
+    // Server has stopped
+    let (dates, status) = fetch().await.unwrap();
+    assert_eq!(dates.len(), 1);
+    assert!(matches!(status, CacheStatus::Fallback { .. }));
+
+    let (dates, age) = cache::read_mirrored_urls(&cache_url, &folder)
+        .unwrap()
+        .unwrap();
+    assert_eq!(dates.len(), 1);
+    assert!(age < Duration::from_secs(60));
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn cache_format_works() {
+    let folder = std::env::temp_dir().join(format!("everygarf-test-format-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let cache_file = folder.join("cache");
+    let cache_path = cache_file.to_string_lossy().to_string();
+    let client = Client::new();
+    let date = |d| NaiveDate::from_ymd_opt(2001, 9, d).unwrap();
+
+    // Version 1 rows, followed by version 2 rows with some values missing and an unknown column
+    fs::write(
+        &cache_file,
+        "2001-09-10 0123456789abcdef\n\
+         2001-09-11 aaaaaaaaaaaaaaaa\n\
+         # everygarf cache v2: date url size sha256 extra\n\
+         # comment\n\
+         2001-09-11 bbbbbbbbbbbbbbbb 3 - x\n\
+         2001-09-12 cccccccccccccccc 3 BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD\n",
+    )
+    .unwrap();
+    let (dates, _) = cache::fetch_cached_urls(
+        &client,
+        &rate_limit::RateLimiter::default(),
+        &cache_path,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(dates.len(), 3);
+    assert_eq!(dates[&date(10)].size, None);
+    assert_eq!(dates[&date(11)].size, Some(3));
+    assert!(dates[&date(11)].url.ends_with("bbbbbbbbbbbbbbbb"));
+    let entry = &dates[&date(12)];
+    assert!(entry.verify(b"abc").is_ok());
+    assert!(entry.verify(b"abd").is_err());
+    assert!(entry.verify(b"abcd").is_err());
+    assert!(dates[&date(10)].verify(b"anything").is_ok());
+
+    // Cleaning upgrades file to latest version
+    cache::clean_cache_file(&cache_file).unwrap();
+    let file = fs::read_to_string(&cache_file).unwrap();
+    let lines: Vec<_> = file.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "# everygarf cache v2: date url sha256 size dimensions source",
+            "2001-09-10 0123456789abcdef",
+            "2001-09-11 bbbbbbbbbbbbbbbb - 3",
+            "2001-09-12 cccccccccccccccc ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad 3",
+        ]
+    );
+
+    // Newer version is not supported
+    fs::write(&cache_file, "# everygarf cache v3: date url\n").unwrap();
+    let result = cache::fetch_cached_urls(
+        &client,
+        &rate_limit::RateLimiter::default(),
+        &cache_path,
+        None,
+    )
+    .await;
+    assert!(result.is_err_and(|error| error.contains("version 3")));
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn corrupt_cached_image_is_fetched_again() {
+    use api::{LocalSource, Source, SourceStep};
+    use std::io::Write;
+
+    let gif = encode_gif(600, 180);
+    let mut corrupt_gif = gif.clone();
+    *corrupt_gif.last_mut().unwrap() ^= 1;
+    let base_url = serve_stand_in(vec![("/image", "image/gif", gif.clone())]);
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-corrupt-{}", process::id()));
+    let images_folder = folder.join("images");
+    let local_folder = folder.join("local");
+    fs::create_dir_all(&images_folder).unwrap();
+    fs::create_dir_all(&local_folder).unwrap();
+    fs::write(local_folder.join("2001-09-10.gif"), &gif).unwrap();
+    fs::write(local_folder.join("2001-09-12.gif"), &gif).unwrap();
+
+    // Checksum of first date does not match image served
+    let cache_file = folder.join("cache");
+    let entry =
+        |url: String, bytes: &[u8]| cache::CacheEntry::from_image(url, bytes, (600, 180), None);
+    let cache_writer = cache::CacheWriter::new(&cache_file);
+    cache_writer.append(
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        &entry(format!("{}/image", base_url), &corrupt_gif),
+    );
+    cache_writer.append(
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+        &entry(format!("{}/image", base_url), &gif),
+    );
+    cache_writer.finish().unwrap();
+
+    // Local file URL of third date is only read by local source
+    let local = LocalSource::from_name(&local_folder.to_string_lossy())
+        .unwrap()
+        .unwrap();
+    let api::Lookup::Image(file_url) = local.lookup(
+        &comics::GARFIELD,
+        NaiveDate::from_ymd_opt(2001, 9, 12).unwrap(),
+    ) else {
+        panic!("local image not found");
+    };
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&cache_file)
+        .unwrap();
+    writeln!(file, "2001-09-12 {}", file_url).unwrap();
+
+    let sources = [SourceStep::Cache, SourceStep::Source(&local)];
+    let dates = [
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 12).unwrap(),
+    ];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader = test_downloader(
+        &sources,
+        &comics::GARFIELD,
+        &rate_limiter,
+        &images_folder,
+        &dates,
+    );
+    downloader.cache_urls = vec![cache_file.to_string_lossy().to_string()];
+    let report = downloader.download_all_images().await;
+    assert_eq!(report.source_counts.get("cache"), Some(&1));
+    assert_eq!(report.source_counts.get(local.name()), Some(&2));
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn cache_maintenance_works() {
+    let folder = std::env::temp_dir().join(format!("everygarf-test-maintain-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let path = |name: &str| folder.join(name).to_string_lossy().to_string();
+    let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+    fs::write(
+        path("old"),
+        "1978-06-19 aaaa\n1978-06-20 bbbb\n1978-06-22 cccc\n",
+    )
+    .unwrap();
+    fs::write(
+        path("new"),
+        "1978-06-20 dddd\n1978-06-21 eeee\n1978-06-22 cccc\n",
+    )
+    .unwrap();
+
+    let diff = cache::diff_cache_files(&path("old"), &path("new")).unwrap();
+    assert_eq!(diff.removed.len(), 1);
+    assert_eq!(diff.removed[0].0, date(1978, 6, 19));
+    assert_eq!(diff.added.len(), 1);
+    assert_eq!(diff.added[0].0, date(1978, 6, 21));
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].2.url, "dddd");
+
+    // Earlier file takes precedence
+    let count = cache::merge_cache_files(&[path("new"), path("old")], &path("merged")).unwrap();
+    assert_eq!(count, 4);
+    let merged = cache::read_cache_rows(&path("merged")).unwrap();
+    assert_eq!(merged[&date(1978, 6, 20)].url, "dddd");
+    cache::merge_cache_files(&[path("old"), path("new")], &path("merged")).unwrap();
+    let merged = cache::read_cache_rows(&path("merged")).unwrap();
+    assert_eq!(merged[&date(1978, 6, 20)].url, "bbbb");
+    assert!(cache::diff_cache_files(&path("merged"), &path("merged"))
+        .unwrap()
+        .is_empty());
+
+    let stats = cache::cache_file_stats(&merged, &comics::GARFIELD);
+    assert_eq!(
+        stats.years[0],
+        cache::YearCoverage {
+            year: 1978,
+            cached_count: 4,
+            total_count: 196,
+        }
+    );
+    assert_eq!(stats.gaps[0].0, date(1978, 6, 23));
+    assert_eq!(stats.gaps.len(), 1);
+
+    let file = "1978-06-19 aaaa\n\
+                1978-06-19 bbbb\n\
+                1900-01-01 cccc\n\
+                invalid\n\
+                1978-06-20 ftp://example.com/image.gif\n";
+    let problems = cache::verify_cache_file(file, &comics::GARFIELD);
+    let lines: Vec<_> = problems.iter().map(|problem| problem.line_number).collect();
+    assert_eq!(lines, [2, 3, 4, 5]);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn cache_writer_works() {
+    let folder = std::env::temp_dir().join(format!("everygarf-test-writer-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let cache_file = folder.join("cache");
+    let entry = |url: &str| cache::CacheEntry {
+        url: url.to_string(),
+        ..Default::default()
+    };
+
+    // Two writers, as from two runs sharing a cache file, with many jobs each
+    let writers = [
+        cache::CacheWriter::new(&cache_file),
+        cache::CacheWriter::new(&cache_file),
+    ];
+    std::thread::scope(|scope| {
+        for (i, writer) in writers.iter().enumerate() {
+            for day in 1..=20 {
+                scope.spawn(move || {
+                    let date = NaiveDate::from_ymd_opt(2000 + i as i32, 1, day).unwrap();
+                    writer.append(date, &entry(&format!("{:016x}", day)));
+                });
+            }
+        }
+    });
+    for writer in &writers {
+        writer.finish().unwrap();
+    }
+    // Rows appended after writer has stopped are dropped
+    writers[0].append(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(), &entry("x"));
+    assert!(writers[0].finish().is_ok());
+
+    let file = fs::read_to_string(&cache_file).unwrap();
+    let lines: Vec<_> = file.lines().collect();
+    assert_eq!(lines.len(), 41);
+    assert!(lines[0].starts_with("# everygarf cache v2"));
+    assert_eq!(lines[1], "2000-01-01 0000000000000001");
+    assert_eq!(lines[40], "2001-01-20 0000000000000014");
+
+    // Only lock file is left beside cache file
+    let mut names: Vec<_> = fs::read_dir(&folder)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["cache", "cache.lock"]);
+
+    // Invalid rows of existing file are removed, without failing
+    fs::write(&cache_file, file.replace("2000-01-02 ", "2000-13-02 ")).unwrap();
+    let writer = cache::CacheWriter::new(&cache_file);
+    writer.append(NaiveDate::from_ymd_opt(2002, 1, 1).unwrap(), &entry("y"));
+    writer.finish().unwrap();
+    let rows = cache::read_cache_rows(cache_file.to_str().unwrap()).unwrap();
+    assert_eq!(rows.len(), 40);
+    assert!(!rows.contains_key(&NaiveDate::from_ymd_opt(2000, 1, 2).unwrap()));
+
+    // File from newer version is not rewritten
+    let newer = "# everygarf cache v3: date url\n2000-01-01 0000000000000001\n";
+    fs::write(&cache_file, newer).unwrap();
+    assert!(cache::clean_cache_file(&cache_file).is_err());
+    assert_eq!(fs::read_to_string(&cache_file).unwrap(), newer);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn stale_cached_url_is_replaced() {
+    use api::{SourceStep, TemplateSource};
+
+    let gif = encode_gif(600, 180);
+    let image_base_url = serve_stand_in(vec![("/image", "image/gif", gif)]);
+    let page = format!(r#"<img src="{}/image">"#, image_base_url);
+    let page_base_url = serve_stand_in(vec![("/page", "text/html", page.into_bytes())]);
+    let template = TemplateSource::parse_file(&format!(
+        r#"
+        [[source]]
+        name = "mirror"
+        page_url = "{}/page/{{yyyy}}{{mm}}{{dd}}"
+        regex = '"(http://[^"]+)"'
+        "#,
+        page_base_url,
+    ))
+    .unwrap()
+    .remove(0);
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-stale-{}", process::id()));
+    let images_folder = folder.join("images");
+    fs::create_dir_all(&images_folder).unwrap();
+    let cache_file = folder.join("cache");
+    fs::write(
+        &cache_file,
+        format!(
+            "2001-09-10 {0}/gone\n2001-09-11 {0}/image\n",
+            image_base_url
+        ),
+    )
+    .unwrap();
+
+    let cache_writer = cache::CacheWriter::new(&cache_file);
+    let sources = [SourceStep::Cache, SourceStep::Source(&template)];
+    let dates = [NaiveDate::from_ymd_opt(2001, 9, 10).unwrap()];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader = test_downloader(
+        &sources,
+        &comics::GARFIELD,
+        &rate_limiter,
+        &images_folder,
+        &dates,
+    );
+    downloader.single_download_options.cache_writer = Some(&cache_writer);
+    downloader.cache_urls = vec![cache_file.to_string_lossy().to_string()];
+    let report = downloader.download_all_images().await;
+    assert_eq!(report.source_counts.get("mirror"), Some(&1));
+
+    // New URL replaces stale URL
+    let rows = cache::read_cache_rows(&cache_file.to_string_lossy()).unwrap();
+    let row = &rows[&dates[0]];
+    assert_eq!(row.url, format!("{}/image", image_base_url));
+    assert_eq!(row.source.as_deref(), Some("mirror"));
+
+    // Stale URLs are found and evicted
+    fs::write(
+        &cache_file,
+        format!(
+            "2001-09-10 {0}/gone\n2001-09-11 {0}/image\n",
+            image_base_url
+        ),
+    )
+    .unwrap();
+    let rows = cache::read_cache_rows(&cache_file.to_string_lossy()).unwrap();
+    let report = cache::refresh_cache_urls(&Client::new(), &rate_limiter, &rows, 4).await;
+    assert_eq!(report.checked_count, 2);
+    assert_eq!(report.stale, [(dates[0], reqwest::StatusCode::NOT_FOUND)]);
+    assert!(report.failed.is_empty());
+
+    // Blocked URLs may still exist, so are not stale
+    assert!(cache::is_stale_status(reqwest::StatusCode::GONE));
+    assert!(!cache::is_stale_status(reqwest::StatusCode::FORBIDDEN));
+    assert!(!cache::is_stale_status(reqwest::StatusCode::UNAUTHORIZED));
+    assert!(!cache::is_stale_status(
+        reqwest::StatusCode::TOO_MANY_REQUESTS
+    ));
+
+    let stale_rows = rows
+        .into_iter()
+        .filter(|(date, _)| *date == dates[0])
+        .collect();
+    let count = cache::evict_cache_rows(&cache_file.to_string_lossy(), &stale_rows).unwrap();
+    assert_eq!(count, 1);
+    let rows = cache::read_cache_rows(&cache_file.to_string_lossy()).unwrap();
+    assert_eq!(rows.len(), 1);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn compressed_cache_works() {
+    let folder = std::env::temp_dir().join(format!("everygarf-test-compress-{}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    let entry = |url: &str| cache::CacheEntry {
+        url: url.to_string(),
+        ..Default::default()
+    };
+    let date = |d| NaiveDate::from_ymd_opt(2001, 9, d).unwrap();
+
+    for (name, compression, magic) in [
+        ("cache.gz", cache::Compression::Gzip, &[0x1f, 0x8b][..]),
+        ("cache.zst", cache::Compression::Zstd, &[0x28, 0xb5][..]),
+    ] {
+        let cache_file = folder.join(name);
+        let cache_path = cache_file.to_string_lossy().to_string();
+
+        // Appended batches are read as one file
+        let mut batches = compression.encode("2001-09-09 0123456789abcdef\n").unwrap();
+        batches.extend(compression.encode("2001-09-10 0123456789abcdef\n").unwrap());
+        fs::write(&cache_file, batches).unwrap();
+        let rows = cache::read_cache_rows(&cache_path).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        // Written batches are appended, then rewritten as one when finished
+        let writer = cache::CacheWriter::new(&cache_file);
+        writer.append(date(11), &entry("fedcba9876543210"));
+        writer.finish().unwrap();
+        assert!(fs::read(&cache_file).unwrap().starts_with(magic));
+        let rows = cache::read_cache_rows(&cache_path).unwrap();
+        assert_eq!(rows[&date(11)].url, "fedcba9876543210");
+
+        let client = Client::new();
+        let (dates, _) = cache::fetch_cached_urls(
+            &client,
+            &rate_limit::RateLimiter::default(),
+            &cache_path,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(dates.len(), 3);
+    }
+
+    // Remote compression is detected without extension, with and without mirror
+    let gzip = cache::Compression::Gzip
+        .encode("2001-09-10 0123456789abcdef\n")
+        .unwrap();
+    let base_url = serve_stand_in(vec![("/cache", "application/octet-stream", gzip)]);
+    let cache_url = format!("{}/cache", base_url);
+    let client = Client::new();
+    for mirror_folder in [None, Some(folder.join("mirror"))] {
+        let (dates, _) = cache::fetch_cached_urls(
+            &client,
+            &rate_limit::RateLimiter::default(),
+            &cache_url,
+            mirror_folder.as_deref(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(dates.len(), 1);
+    }
+
+    fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn cache_layers_work() {
+    use api::SourceStep;
+
+    let gif = encode_gif(600, 180);
+    let base_url = serve_stand_in(vec![
+        ("/team", "image/gif", gif.clone()),
+        ("/image", "image/gif", gif),
+    ]);
+
+    let folder = std::env::temp_dir().join(format!("everygarf-test-layers-{}", process::id()));
+    let images_folder = folder.join("images");
+    fs::create_dir_all(&images_folder).unwrap();
+    let team_cache = folder.join("team-cache").to_string_lossy().to_string();
+    let upstream_cache = folder.join("upstream-cache").to_string_lossy().to_string();
+    // Corrected URL overrides broken upstream URL
+    fs::write(&team_cache, format!("2001-09-10 {0}/team\n", base_url)).unwrap();
+    fs::write(
+        &upstream_cache,
+        format!("2001-09-10 {0}/broken\n2001-09-11 {0}/image\n", base_url),
+    )
+    .unwrap();
+
+    let sources = [SourceStep::Cache];
+    let dates = [
+        NaiveDate::from_ymd_opt(2001, 9, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2001, 9, 11).unwrap(),
+    ];
+    let rate_limiter = rate_limit::RateLimiter::default();
+    let mut downloader = test_downloader(
+        &sources,
+        &comics::GARFIELD,
+        &rate_limiter,
+        &images_folder,
+        &dates,
+    );
+    downloader.job_count = 2;
+    downloader.cache_urls = vec![team_cache.clone(), upstream_cache.clone()];
+
+    let plan = downloader.plan_offline().await.unwrap();
+    let expected_counts = [(team_cache.clone(), 1), (upstream_cache.clone(), 1)];
+    assert_eq!(plan.cache_layer_counts, expected_counts);
+
+    // Precedence is reversed
+    downloader.cache_urls.reverse();
+    let plan = downloader.plan_offline().await.unwrap();
+    assert_eq!(
+        plan.cache_layer_counts,
+        [(upstream_cache.clone(), 2), (team_cache.clone(), 0)]
+    );
+    downloader.cache_urls.reverse();
+
+    let report = downloader.download_all_images().await;
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.source_counts.get("cache"), Some(&2));
+    assert_eq!(report.cache_layer_counts, expected_counts);
+
+    fs::remove_dir_all(&folder).unwrap();
+}
@@ -0,0 +1,102 @@
+use reqwest::{Certificate, ClientBuilder, Identity};
+use std::fs;
+use std::path::Path;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either `native-tls` or `rustls` feature must be enabled");
+
+/// TLS settings for all requests, eg. for networks with TLS interception
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// Trusted in addition to system (or built-in) root certificates
+    root_certificates: Vec<Certificate>,
+    /// Client certificate to present to servers
+    identity: Option<Identity>,
+    /// Accept invalid certificates. Only for local testing
+    insecure: bool,
+}
+
+impl TlsConfig {
+    /// Load root certificates from a PEM bundle
+    pub fn with_ca_bundle(mut self, path: &Path) -> Result<Self, String> {
+        let file = read_pem_file(path)?;
+        let certificates = Certificate::from_pem_bundle(&file)
+            .map_err(|error| format!("Parsing CA bundle `{}` - {}", path.display(), error))?;
+        if certificates.is_empty() {
+            return Err(format!(
+                "No certificates found in CA bundle `{}`",
+                path.display()
+            ));
+        }
+        self.root_certificates.extend(certificates);
+        Ok(self)
+    }
+
+    /// Load client certificate and private key from PEM files
+    ///
+    /// Key may be in the same file as certificate. Key must be PKCS#8 with `native-tls`
+    pub fn with_client_cert(
+        mut self,
+        cert_path: &Path,
+        key_path: Option<&Path>,
+    ) -> Result<Self, String> {
+        let cert = read_pem_file(cert_path)?;
+        let key = match key_path {
+            Some(key_path) => read_pem_file(key_path)?,
+            None => cert.clone(),
+        };
+        let identity = build_identity(&cert, &key).map_err(|error| {
+            format!(
+                "Parsing client certificate `{}` - {}",
+                cert_path.display(),
+                error
+            )
+        })?;
+        self.identity = Some(identity);
+        Ok(self)
+    }
+
+    /// Disable certificate verification
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    pub fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        #[cfg(feature = "rustls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        builder.danger_accept_invalid_certs(self.insecure)
+    }
+}
+
+fn read_pem_file(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|error| format!("Reading `{}` - {}", path.display(), error))
+}
+
+#[cfg(feature = "rustls")]
+fn build_identity(cert: &[u8], key: &[u8]) -> reqwest::Result<Identity> {
+    if cert == key {
+        return Identity::from_pem(cert);
+    }
+    let mut pem = cert.to_vec();
+    pem.push(b'\n');
+    pem.extend_from_slice(key);
+    Identity::from_pem(&pem)
+}
+
+#[cfg(not(feature = "rustls"))]
+fn build_identity(cert: &[u8], key: &[u8]) -> reqwest::Result<Identity> {
+    Identity::from_pkcs8_pem(cert, key)
+}